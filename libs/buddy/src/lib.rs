@@ -1,222 +1,995 @@
 #![feature(const_fn)]
 #![feature(associated_consts)]
 #![feature(allocator)]
+#![cfg_attr(test, feature(test))]
+#![cfg_attr(feature = "trace", feature(link_llvm_intrinsics))]
 
-#![allocator]
-#![no_std]
+#![cfg_attr(not(test), allocator)]
+#![cfg_attr(not(test), no_std)]
 
+#[cfg(test)]
+extern crate core;
 extern crate spin;
-#[macro_use]
-extern crate lazy_static;
 
+use core::{cmp, fmt, ptr};
+use slab::SlabAllocator;
 use spin::Mutex;
 
+pub use slab::{CacheStats, CACHES};
+
+mod slab;
+#[cfg(feature = "trace")]
+pub mod trace;
+
+/// Keeps the allocation paths free of conditional compilation when tracing is disabled.
+#[cfg(not(feature = "trace"))]
+mod trace {
+    pub fn caller() -> usize {
+        0
+    }
+    pub fn record(_: *mut u8, _: usize, _: usize) {}
+    pub fn resize(_: *mut u8, _: usize) {}
+    pub fn forget(_: *mut u8) {}
+}
+
 pub const BASE: usize = 0x4000000;
-pub const SIZE: usize = Block::SIZE * (1 << BuddyAllocator::ORDER);
+/// The size of a region, the unit by which the heap grows and shrinks.
+pub const REGION: usize = Block::SIZE * (1 << BuddyAllocator::ORDER);
+/// The size the heap can grow to at most.
+pub const SIZE: usize = REGION * BuddyAllocator::REGIONS;
+
+/// The amount of different block sizes.
+pub const ORDERS: usize = BuddyAllocator::ORDER + 1;
+
+/// The amount of words needed to hold one bit for every block of every order.
+const MAP_SIZE: usize = 2 * (SIZE / Block::SIZE) / 64;
 
-lazy_static! {
-    static ref ALLOCATOR: Mutex<BuddyAllocator> = Mutex::new(BuddyAllocator::new());
+static ALLOCATOR: Mutex<BuddyAllocator> = Mutex::new(BuddyAllocator::new(BASE));
+static SLAB: Mutex<SlabAllocator> = Mutex::new(SlabAllocator::new());
+
+/// Called when the heap cannot satisfy a request. Receives the size and alignment of the request
+/// and returns whether memory was reclaimed, in which case the allocation is retried.
+static OOM_HANDLER: Mutex<Option<fn(usize, usize) -> bool>> = Mutex::new(None);
+
+/// Called with the start address and size of a region before it is added to the heap. Returns
+/// whether the memory of the region could be made available.
+static GROW_HANDLER: Mutex<Option<fn(usize, usize) -> bool>> = Mutex::new(None);
+
+/// Called with the start address and size of a region after it was removed from the heap.
+static SHRINK_HANDLER: Mutex<Option<fn(usize, usize) -> bool>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllocError {
+    /// The request is larger than a whole region.
+    TooLarge,
+    /// There is no free block left that could hold the request.
+    Exhausted,
 }
 
+/// A buddy allocator that keeps a doubly linked list of free blocks for every order, stored in
+/// the free blocks themselves, so allocating and freeing only takes a step per order.
+///
+/// Every block is aligned to its own size, which makes any power-of-two alignment up to the size
+/// of a region free to honor. Larger alignments are served from whole regions that happen to be
+/// aligned accordingly.
 pub struct BuddyAllocator {
-    blocks: [Block; SIZE / Block::SIZE],
+    /// The address the first region starts at.
+    base: usize,
+    /// The address of the first free block of each order, or 0 if there is none.
+    free: [usize; ORDERS],
+    /// The amount of blocks on the free list of each order.
+    counts: [usize; ORDERS],
+    /// Holds a bit for every block of every order, that is set if the block is on a free list.
+    map: [u64; MAP_SIZE],
+    /// The amount of regions that are currently part of the heap.
+    regions: usize,
+    /// The amount of bytes currently handed out.
+    used: usize,
+    /// The largest amount of bytes that were handed out at once.
+    high_water: usize,
+    allocations: usize,
+    deallocations: usize,
 }
 
 impl BuddyAllocator {
-    const ORDER: u8 = 9;
+    const ORDER: usize = 9;
+    const REGIONS: usize = 32;
 
-    pub fn new() -> Self {
+    pub const fn new(base: usize) -> Self {
         BuddyAllocator {
-            blocks: [Block {
-                order: BuddyAllocator::ORDER,
-                used: false,
-            }; SIZE / Block::SIZE],
+            base: base,
+            free: [0; ORDERS],
+            counts: [0; ORDERS],
+            map: [0; MAP_SIZE],
+            regions: 0,
+            used: 0,
+            high_water: 0,
+            allocations: 0,
+            deallocations: 0,
         }
     }
 
-    pub fn allocate(&mut self, size: usize, align: usize) -> *mut u8 {
-        // Check if we can even fit the request on the heap.
-        assert!(size <= SIZE, "Request exceeds maximum allocation unit.");
+    pub fn allocate(&mut self, size: usize, align: usize) -> Result<*mut u8, AllocError> {
         assert!(align & (align - 1) == 0, "Alignment is off boundary.");
 
-        // Calculate the order of blocks we need and try to find a fit.
-        let order = BuddyAllocator::order(size);
-        let index = self.fit(order).unwrap_or_else(|| {
-            // Search for a higher order block and split it if necessary.
-            for i in order..(BuddyAllocator::ORDER + 1) {
-                let opt = self.fit(i);
-                match opt {
-                    Some(index) => {
-                        for _ in 0..(i - order) {
-                            self.split(index);
-                        }
-                        return index;
-                    }
-                    None => continue,
-                }
-            }
-            // TODO: This should not panic, but rather return an Err.
-            panic!("Could not fit request into current memory scheme.");
-        });
+        // Check if we can even fit the request into a region.
+        if size > REGION {
+            return Err(AllocError::TooLarge);
+        }
 
-        // Mark all of the now reserved blocks as used.
-        self.set(index, order, true);
+        // Find the lowest order that can hold the request and still has a suitable free block.
+        let order = BuddyAllocator::order(size, align);
+        let mut current = order;
+        let mut found = None;
+        while current <= BuddyAllocator::ORDER {
+            found = self.find(current, align);
+            if found.is_some() {
+                break;
+            }
+            current += 1;
+        }
+        let addr = match found {
+            Some(addr) => addr,
+            None => return Err(AllocError::Exhausted),
+        };
+
+        // Split the block down to the requested order, freeing the right halves.
+        self.remove(addr, current);
+        while current > order {
+            current -= 1;
+            self.insert(addr + BuddyAllocator::size(current), current);
+        }
 
-        // Return a pointer to the first of the reversed blocks.
-        unsafe { (BASE as *mut u8).offset((index * Block::SIZE) as isize) }
+        self.used += BuddyAllocator::size(order);
+        self.high_water = cmp::max(self.high_water, self.used);
+        self.allocations += 1;
+        Ok(addr as *mut u8)
     }
 
     pub fn deallocate(&mut self, ptr: *mut u8, size: usize, align: usize) {
-        assert!((ptr as usize) < BASE + SIZE && (ptr as usize) >= BASE,
+        assert!((ptr as usize) < self.base + self.regions * REGION && (ptr as usize) >= self.base,
                 "Could not deallocate pointer outside of the heap.");
-        let index = ((ptr as usize) - BASE) / Block::SIZE;
-        let order = self.blocks[index].order;
+        let mut addr = ptr as usize;
+        let mut order = BuddyAllocator::order(size, align);
+        self.used -= BuddyAllocator::size(order);
+        self.deallocations += 1;
+
+        // Merge with the buddy for as long as it is free as a whole.
+        while order < BuddyAllocator::ORDER {
+            let buddy = self.buddy(addr, order);
+            if !self.is_free(buddy, order) {
+                break;
+            }
 
-        // Mark the blocks as now unused.
-        self.set(index, order, false);
+            self.remove(buddy, order);
+            addr = cmp::min(addr, buddy);
+            order += 1;
+        }
 
-        if self.is_left(index, order) {
-            // Check if the right block is used.
-            if !self.blocks[index + (1 << order)].used {
-                self.merge(index);
+        self.insert(addr, order);
+    }
+
+    /// Tries to resize the allocation at `ptr` without moving it, and returns whether it
+    /// succeeded.
+    ///
+    /// Shrinking always succeeds, since the halves that are no longer needed are simply freed.
+    /// Growing only succeeds if the block is the left half of each larger block it would grow
+    /// into, and all right halves are free.
+    pub fn reallocate_inplace(&mut self,
+                              ptr: *mut u8,
+                              old_size: usize,
+                              size: usize,
+                              align: usize)
+                              -> bool {
+        if size > REGION {
+            return false;
+        }
+
+        let addr = ptr as usize;
+        let old = BuddyAllocator::order(old_size, align);
+        let new = BuddyAllocator::order(size, align);
+
+        if new < old {
+            for order in new..old {
+                self.insert(addr + BuddyAllocator::size(order), order);
             }
-        } else {
-            // Check if the left block is used.
-            if !self.blocks[index - (1 << order)].used {
-                self.merge(index - (1 << order));
+            self.used -= BuddyAllocator::size(old) - BuddyAllocator::size(new);
+        } else if new > old {
+            for order in old..new {
+                let buddy = self.buddy(addr, order);
+                if buddy < addr || !self.is_free(buddy, order) {
+                    return false;
+                }
             }
+            for order in old..new {
+                self.remove(addr + BuddyAllocator::size(order), order);
+            }
+            self.used += BuddyAllocator::size(new) - BuddyAllocator::size(old);
+            self.high_water = cmp::max(self.high_water, self.used);
         }
+        true
     }
 
     pub fn zero(ptr: *mut u8, size: usize) {
-        let mut p = ptr;
-        for _ in 0..size {
-            unsafe {
-                *p = 0;
-                p = ptr.offset(1);
-            }
+        unsafe { ptr::write_bytes(ptr, 0, size) };
+    }
+
+    /// Returns the amount of bytes that are actually available to a request of the given size
+    /// and alignment.
+    pub fn usable_size(size: usize, align: usize) -> usize {
+        BuddyAllocator::size(BuddyAllocator::order(size, align))
+    }
+
+    /// Returns the amount of bytes not currently handed out.
+    pub fn free(&self) -> usize {
+        self.regions * REGION - self.used
+    }
+
+    pub fn stats(&self) -> HeapStats {
+        HeapStats {
+            size: self.regions * REGION,
+            used: self.used,
+            high_water: self.high_water,
+            allocations: self.allocations,
+            outstanding: self.allocations - self.deallocations,
+            free_blocks: self.counts,
         }
     }
 
-    fn order(size: usize) -> u8 {
+    /// Returns the start address and size of the region that would be added next, if the heap
+    /// can still grow.
+    pub fn next_region(&self) -> Option<(usize, usize)> {
+        if self.regions < BuddyAllocator::REGIONS {
+            Some((self.base + self.regions * REGION, REGION))
+        } else {
+            None
+        }
+    }
+
+    /// Adds the region returned by [`next_region`](#method.next_region) to the heap.
+    ///
+    /// The memory of the region has to be accessible before calling this.
+    pub fn grow(&mut self) {
+        assert!(self.base % REGION == 0,
+                "Heap has to start at an address aligned to the size of a region.");
+        let (start, _) = self.next_region()
+            .expect("Heap cannot grow beyond its maximum size.");
+        self.regions += 1;
+        self.insert(start, BuddyAllocator::ORDER);
+    }
+
+    /// Removes the last region from the heap if none of its memory is in use, and returns its
+    /// start address and size. The first region is never given back.
+    ///
+    /// A free last region is kept as a spare as long as the region before it is in use, so a heap
+    /// whose use moves back and forth across the end of a region does not map and unmap the
+    /// region on every allocation.
+    pub fn shrink(&mut self) -> Option<(usize, usize)> {
+        if self.regions <= 1 {
+            return None;
+        }
+
+        let start = self.base + (self.regions - 1) * REGION;
+        if !self.is_free(start, BuddyAllocator::ORDER)
+            || !self.is_free(start - REGION, BuddyAllocator::ORDER)
+        {
+            return None;
+        }
+
+        self.remove(start, BuddyAllocator::ORDER);
+        self.regions -= 1;
+        Some((start, REGION))
+    }
+
+    /// Returns the order of the blocks that satisfy a request of the given size and alignment.
+    fn order(size: usize, align: usize) -> usize {
+        let size = cmp::max(size, cmp::min(align, REGION));
         let mut i = 0;
-        while size > (Block::SIZE * (1 << i)) {
+        while size > BuddyAllocator::size(i) {
             i += 1;
         }
         i
     }
 
-    fn fit(&self, order: u8) -> Option<usize> {
-        assert!(order <= BuddyAllocator::ORDER,
-                "Order exceeds the maximum order of the allocator.");
-        for (i, block) in self.blocks.iter().enumerate() {
-            if order == block.order && !block.used {
-                return Some(i);
+    /// Returns the first free block of the given order that is aligned to `align`.
+    fn find(&self, order: usize, align: usize) -> Option<usize> {
+        let mut addr = self.free[order];
+        while addr != 0 {
+            if addr % align == 0 {
+                return Some(addr);
             }
+            addr = unsafe { (*(addr as *const Block)).next };
         }
         None
     }
 
-    fn split(&mut self, index: usize) {
-        let order = self.blocks[index].order;
-        assert!(index % (1 << order) == 0,
-                "Index is not properly aligned for its order.");
+    /// Returns the size of a block of the given order.
+    fn size(order: usize) -> usize {
+        Block::SIZE << order
+    }
 
-        for i in 0..(1 << order) {
-            assert!(!self.blocks[index + i].used,
-                    "Unable to split a block currently in use.");
-            self.blocks[index + i].order -= 1;
-        }
+    /// Returns the address of the buddy of the block at the given address.
+    fn buddy(&self, addr: usize, order: usize) -> usize {
+        let index = (addr - self.base) / Block::SIZE;
+        self.base + (index ^ (1 << order)) * Block::SIZE
     }
 
-    fn merge(&mut self, index: usize) {
-        let order = self.blocks[index].order;
-        assert!(index % (1 << order) == 0,
-                "Index is not properly aligned for its order.");
+    /// Returns the position of the bit in the map that belongs to the given block.
+    fn bit(&self, addr: usize, order: usize) -> usize {
+        assert!(order <= BuddyAllocator::ORDER,
+                "Order exceeds the maximum order of the allocator.");
 
-        for i in 0..(1 << (order + 1)) {
-            assert!(!self.blocks[index + i].used,
-                    "Unable to merge two blocks currently in use.");
-            self.blocks[index + i].order += 1;
+        // The bits of an order follow the ones of all lower orders.
+        let mut offset = 0;
+        for i in 0..order {
+            offset += (SIZE / Block::SIZE) >> i;
         }
+        offset + (((addr - self.base) / Block::SIZE) >> order)
+    }
 
-        if order + 1 != BuddyAllocator::ORDER {
-            // We are left per definition.
-            if !self.blocks[index + (1 << (order + 1))].used {
-                self.merge(index);
-            }
+    fn is_free(&self, addr: usize, order: usize) -> bool {
+        let bit = self.bit(addr, order);
+        self.map[bit / 64] & (1 << (bit % 64)) != 0
+    }
 
+    /// Puts the block at the given address on the free list of its order.
+    fn insert(&mut self, addr: usize, order: usize) {
+        let next = self.free[order];
+        unsafe {
+            *(addr as *mut Block) = Block {
+                prev: 0,
+                next: next,
+            };
+            if next != 0 {
+                (*(next as *mut Block)).prev = addr;
+            }
         }
-    }
+        self.free[order] = addr;
+        self.counts[order] += 1;
 
-    fn is_left(&self, index: usize, order: u8) -> bool {
-        assert!(order <= BuddyAllocator::ORDER,
-                "Order exceeds the maximum order of the allocator.");
+        let bit = self.bit(addr, order);
+        self.map[bit / 64] |= 1 << (bit % 64);
+    }
 
-        assert!(index % (1 << order) == 0,
-                "Index is not properly aligned for its order.");
+    /// Takes the block at the given address off the free list of its order.
+    fn remove(&mut self, addr: usize, order: usize) {
+        let block = unsafe { *(addr as *const Block) };
+        if block.prev == 0 {
+            self.free[order] = block.next;
+        } else {
+            unsafe { (*(block.prev as *mut Block)).next = block.next };
+        }
+        if block.next != 0 {
+            unsafe { (*(block.next as *mut Block)).prev = block.prev };
+        }
+        self.counts[order] -= 1;
 
-        index % (2 * (1 << order)) == 0
+        let bit = self.bit(addr, order);
+        self.map[bit / 64] &= !(1 << (bit % 64));
     }
+}
 
-    fn set(&mut self, index: usize, order: u8, used: bool) {
-        assert!(order <= BuddyAllocator::ORDER,
-                "Order exceeds the maximum order of the allocator.");
+/// A snapshot of the usage of the heap. Slabs count as single allocations here, the objects in
+/// them are covered by the statistics of their caches.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// The amount of bytes the heap currently spans.
+    pub size: usize,
+    /// The amount of bytes handed out, including what requests were rounded up by.
+    pub used: usize,
+    /// The largest amount of bytes that were handed out at once.
+    pub high_water: usize,
+    /// The amount of allocations served since boot.
+    pub allocations: usize,
+    /// The amount of allocations that have not been freed yet.
+    pub outstanding: usize,
+    /// The amount of free blocks of each order.
+    pub free_blocks: [usize; ORDERS],
+}
 
-        for i in 0..(1 << order) {
-            self.blocks[index + i].used = used;
+impl HeapStats {
+    /// Returns the size of the largest free block.
+    pub fn largest_free(&self) -> usize {
+        match (0..ORDERS).rev().find(|&order| self.free_blocks[order] > 0) {
+            Some(order) => BuddyAllocator::size(order),
+            None => 0,
+        }
+    }
+
+    /// Returns the share of free memory that is not part of the largest free block, in percent.
+    pub fn fragmentation(&self) -> usize {
+        let free = self.size - self.used;
+        if free == 0 {
+            0
+        } else {
+            100 - self.largest_free() * 100 / free
         }
     }
 }
 
+impl fmt::Display for HeapStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}/{} bytes used (at most {}), {} of {} allocations outstanding, largest free \
+                block {} bytes, {}% fragmented",
+               self.used,
+               self.size,
+               self.high_water,
+               self.outstanding,
+               self.allocations,
+               self.largest_free(),
+               self.fragmentation())
+    }
+}
+
+/// The header of a free block, linking it to its neighbours on the free list.
 #[derive(Debug, Clone, Copy)]
 pub struct Block {
-    order: u8,
-    used: bool,
+    prev: usize,
+    next: usize,
 }
 
 impl Block {
-    pub const SIZE: usize = 4000; // 4KiB
+    pub const SIZE: usize = 4096; // 4KiB
+}
+
+/// Registers the function that is called whenever the heap runs out of memory.
+///
+/// The handler must not allocate, and should only return `true` if it actually freed memory,
+/// since the failed request is retried for as long as it does.
+pub fn set_oom_handler(handler: fn(usize, usize) -> bool) {
+    *OOM_HANDLER.lock() = Some(handler);
+}
+
+/// Registers the functions that make the memory of a region available before the heap grows
+/// into it, and release it again once the heap shrinks. The heap is empty until `grow` has
+/// succeeded at least once.
+///
+/// `shrink` returns whether the memory has been released. If it cannot release it right now, the
+/// region stays part of the heap and is given back on a later deallocation.
+///
+/// Neither function may allocate.
+pub fn set_region_handlers(grow: fn(usize, usize) -> bool, shrink: fn(usize, usize) -> bool) {
+    *GROW_HANDLER.lock() = Some(grow);
+    *SHRINK_HANDLER.lock() = Some(shrink);
+}
+
+/// Returns the amount of bytes on the heap that are not in use.
+pub fn free() -> usize {
+    ALLOCATOR.lock().free()
+}
+
+/// Returns the usage statistics of the blocks on the heap.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
+/// Returns the usage statistics of every slab cache, smallest objects first. The caches stay
+/// empty unless the `slab` feature is enabled.
+pub fn slab_stats() -> [CacheStats; CACHES] {
+    SLAB.lock().stats()
+}
+
+/// Returns the slab cache that serves requests of the given size and alignment, if the slab
+/// layer is enabled and the request is small enough.
+fn cache(size: usize, align: usize) -> Option<usize> {
+    if cfg!(feature = "slab") {
+        SlabAllocator::cache(size, align)
+    } else {
+        None
+    }
+}
+
+/// Tries to add another region to the heap and returns whether it succeeded.
+fn grow() -> bool {
+    let region = ALLOCATOR.lock().next_region();
+    let handler = *GROW_HANDLER.lock();
+    match (region, handler) {
+        (Some((start, size)), Some(handler)) if handler(start, size) => {
+            ALLOCATOR.lock().grow();
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Gives back the regions at the end of the heap that are no longer in use, except for a spare one.
+fn shrink() {
+    let handler = *SHRINK_HANDLER.lock();
+    if let Some(handler) = handler {
+        loop {
+            let region = ALLOCATOR.lock().shrink();
+            match region {
+                Some((start, size)) if handler(start, size) => {}
+                Some(_) => {
+                    // Keep the region until the handler is able to release it.
+                    ALLOCATOR.lock().grow();
+                    break;
+                }
+                None => break,
+            }
+        }
+    }
+}
+
+/// Returns the amount of bytes available to a request of the given size and alignment, which is
+/// served by the given slab cache, or by the buddy heap if there is none.
+fn usable_size(size: usize, align: usize, cache: Option<usize>) -> usize {
+    match cache {
+        Some(cache) => SlabAllocator::size(cache),
+        None => BuddyAllocator::usable_size(size, align),
+    }
+}
+
+/// Resizes an object in place, given the slab caches that serve its old and its new size, and
+/// returns the amount of bytes available to it afterwards. A result smaller than `size` tells the
+/// caller to move the object.
+fn reallocate_inplace(heap: &mut BuddyAllocator,
+                      ptr: *mut u8,
+                      old_size: usize,
+                      size: usize,
+                      align: usize,
+                      caches: (Option<usize>, Option<usize>))
+                      -> usize {
+    // Objects never move between caches or between a cache and the heap, and slabs are never
+    // resized.
+    let resized = match caches {
+        (Some(old), Some(new)) => old == new,
+        (None, None) => heap.reallocate_inplace(ptr, old_size, size, align),
+        _ => false,
+    };
+    if resized {
+        return usable_size(size, align, caches.1);
+    }
+
+    // The object may still be large enough, but it has to be freed with its new size, which would
+    // hand it to the wrong cache.
+    let usable = usable_size(old_size, align, caches.0);
+    if usable < size { usable } else { 0 }
 }
 
+fn allocate(size: usize, align: usize) -> Result<*mut u8, AllocError> {
+    loop {
+        // The allocator has to be unlocked before calling the handlers, so they can inspect the
+        // heap.
+        let result = match cache(size, align) {
+            Some(cache) => SLAB.lock().allocate(cache, &mut ALLOCATOR.lock()),
+            None => ALLOCATOR.lock().allocate(size, align),
+        };
+        if result != Err(AllocError::Exhausted) {
+            return result;
+        }
+        if grow() || SLAB.lock().reclaim(&mut ALLOCATOR.lock()) {
+            continue;
+        }
+
+        let handler = *OOM_HANDLER.lock();
+        match handler {
+            Some(handler) if handler(size, align) => continue,
+            _ => return result,
+        }
+    }
+}
+
+fn deallocate(ptr: *mut u8, size: usize, align: usize) {
+    match cache(size, align) {
+        Some(cache) => SLAB.lock().deallocate(ptr, cache, &mut ALLOCATOR.lock()),
+        None => ALLOCATOR.lock().deallocate(ptr, size, align),
+    }
+    shrink();
+    trace::forget(ptr);
+}
+
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
-    ALLOCATOR.lock().allocate(size, align)
+    let ptr = allocate(size, align).unwrap_or(ptr::null_mut());
+    trace::record(ptr, size, trace::caller());
+    ptr
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn __rust_allocate_zeroed(size: usize, align: usize) -> *mut u8 {
-    let ptr = ALLOCATOR.lock().allocate(size, align);
-    BuddyAllocator::zero(ptr, size);
+    let ptr = allocate(size, align).unwrap_or(ptr::null_mut());
+    if !ptr.is_null() {
+        BuddyAllocator::zero(ptr, size);
+    }
+    trace::record(ptr, size, trace::caller());
     ptr
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn __rust_reallocate(ptr: *mut u8,
                                     old_size: usize,
                                     size: usize,
                                     align: usize)
                                     -> *mut u8 {
-    let new_ptr = ALLOCATOR.lock().allocate(size, align);
-    ALLOCATOR.lock().deallocate(ptr, old_size, align);
+    if __rust_reallocate_inplace(ptr, old_size, size, align) >= size {
+        trace::resize(ptr, size);
+        return ptr;
+    }
+
+    let new_ptr = allocate(size, align).unwrap_or(ptr::null_mut());
+    if !new_ptr.is_null() {
+        unsafe { ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(old_size, size)) };
+        deallocate(ptr, old_size, align);
+        trace::record(new_ptr, size, trace::caller());
+    }
     new_ptr
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn __rust_reallocate_inplace(ptr: *mut u8,
                                             old_size: usize,
                                             size: usize,
                                             align: usize)
                                             -> usize {
-    size
+    let caches = (cache(old_size, align), cache(size, align));
+    reallocate_inplace(&mut ALLOCATOR.lock(), ptr, old_size, size, align, caches)
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn __rust_deallocate(ptr: *mut u8, size: usize, align: usize) {
-    ALLOCATOR.lock().deallocate(ptr, size, align);
+    deallocate(ptr, size, align);
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn __rust_usable_size(size: usize, align: usize) -> usize {
-    size
+    usable_size(size, align, cache(size, align))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reallocate_inplace, AllocError, Block, BuddyAllocator, SlabAllocator, REGION};
+
+    /// Builds an allocator with the given amount of regions on top of a buffer on the host heap.
+    pub fn heap(regions: usize) -> (Vec<u64>, Box<BuddyAllocator>) {
+        // Allocate an extra region, so the heap can start on a region boundary.
+        let memory = vec![0u64; (regions + 1) * REGION / 8];
+        let base = (memory.as_ptr() as usize + REGION - 1) & !(REGION - 1);
+        let mut allocator = Box::new(BuddyAllocator::new(base));
+        for _ in 0..regions {
+            allocator.grow();
+        }
+        (memory, allocator)
+    }
+
+    /// A xorshift generator, so randomized tests do not need any dependencies and are
+    /// reproducible.
+    pub struct Rng(u64);
+
+    impl Rng {
+        pub fn new() -> Rng {
+            Rng(0x2545_f491_4f6c_dd1d)
+        }
+
+        /// Returns a number in `0..bound`.
+        pub fn below(&mut self, bound: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % bound as u64) as usize
+        }
+    }
+
+    /// Asserts that the allocation does not overlap with any of the given ones.
+    pub fn assert_disjoint(ptr: *mut u8, size: usize, allocations: &[(*mut u8, usize, usize)]) {
+        let start = ptr as usize;
+        for &(other, other_size, _) in allocations {
+            let other = other as usize;
+            assert!(start + size <= other || other + other_size <= start,
+                    "{:#x}+{} overlaps {:#x}+{}",
+                    start,
+                    size,
+                    other,
+                    other_size);
+        }
+    }
+
+    #[test]
+    fn order() {
+        assert_eq!(BuddyAllocator::order(0, 1), 0);
+        assert_eq!(BuddyAllocator::order(Block::SIZE, 1), 0);
+        assert_eq!(BuddyAllocator::order(Block::SIZE + 1, 1), 1);
+        assert_eq!(BuddyAllocator::order(1, 4 * Block::SIZE), 2);
+        assert_eq!(BuddyAllocator::order(REGION, 1), BuddyAllocator::ORDER);
+        assert_eq!(BuddyAllocator::order(1, 4 * REGION), BuddyAllocator::ORDER);
+    }
+
+    #[test]
+    fn buddy() {
+        let (_memory, allocator) = heap(1);
+        let base = allocator.base;
+
+        assert_eq!(allocator.buddy(base, 0), base + Block::SIZE);
+        assert_eq!(allocator.buddy(base + Block::SIZE, 0), base);
+        assert_eq!(allocator.buddy(base + 2 * Block::SIZE, 1), base);
+        assert_eq!(allocator.buddy(base + 4 * Block::SIZE, 1), base + 6 * Block::SIZE);
+    }
+
+    #[test]
+    fn split_and_merge() {
+        let (_memory, mut allocator) = heap(1);
+        let base = allocator.base;
+
+        // A single block splits the region once per order, freeing every right half.
+        let ptr = allocator.allocate(Block::SIZE, 1).unwrap();
+        assert_eq!(ptr as usize, base);
+        for order in 0..BuddyAllocator::ORDER {
+            assert!(allocator.is_free(base + BuddyAllocator::size(order), order));
+        }
+        assert!(!allocator.is_free(base, BuddyAllocator::ORDER));
+
+        // Freeing it merges everything back into the region.
+        allocator.deallocate(ptr, Block::SIZE, 1);
+        assert!(allocator.is_free(base, BuddyAllocator::ORDER));
+        for order in 0..BuddyAllocator::ORDER {
+            assert!(!allocator.is_free(base + BuddyAllocator::size(order), order));
+            assert_eq!(allocator.free[order], 0);
+        }
+    }
+
+    #[test]
+    fn allocate_aligned() {
+        let (_memory, mut allocator) = heap(4);
+        for shift in 0..23 {
+            let align = 1 << shift;
+            let ptr = allocator.allocate(1, align).unwrap();
+            assert_eq!(ptr as usize % align, 0, "{:p} not aligned to {}", ptr, align);
+            allocator.deallocate(ptr, 1, align);
+        }
+    }
+
+    #[test]
+    fn allocate_too_large() {
+        let (_memory, mut allocator) = heap(2);
+        assert_eq!(allocator.allocate(REGION + 1, 1), Err(AllocError::TooLarge));
+    }
+
+    #[test]
+    fn allocate_exhausted() {
+        let (_memory, mut allocator) = heap(1);
+        let ptr = allocator.allocate(REGION / 2, 1).unwrap();
+        assert!(allocator.allocate(REGION / 2, 1).is_ok());
+        assert_eq!(allocator.allocate(Block::SIZE, 1), Err(AllocError::Exhausted));
+
+        allocator.deallocate(ptr, REGION / 2, 1);
+        assert!(allocator.allocate(Block::SIZE, 1).is_ok());
+    }
+
+    #[test]
+    fn shrink() {
+        let (_memory, mut allocator) = heap(3);
+        let base = allocator.base;
+        let ptr = allocator.allocate(REGION, REGION).unwrap();
+        assert_eq!(ptr as usize, base + 2 * REGION);
+
+        // The last region is in use, so nothing can be given back.
+        assert_eq!(allocator.shrink(), None);
+
+        allocator.deallocate(ptr, REGION, REGION);
+        assert_eq!(allocator.shrink(), Some((base + 2 * REGION, REGION)));
+        assert_eq!(allocator.shrink(), Some((base + REGION, REGION)));
+        assert_eq!(allocator.shrink(), None);
+        assert_eq!(allocator.free(), REGION);
+    }
+
+    #[test]
+    fn shrink_keeps_spare_region() {
+        let (_memory, mut allocator) = heap(3);
+        let base = allocator.base;
+        let mut regions = [0; 3];
+        for region in regions.iter_mut() {
+            *region = allocator.allocate(REGION, REGION).unwrap() as usize;
+        }
+        regions.sort();
+        assert_eq!(regions, [base, base + REGION, base + 2 * REGION]);
+
+        // The last region stays as a spare while the one before it is in use.
+        allocator.deallocate(regions[2] as *mut u8, REGION, REGION);
+        assert_eq!(allocator.shrink(), None);
+
+        allocator.deallocate(regions[1] as *mut u8, REGION, REGION);
+        assert_eq!(allocator.shrink(), Some((base + 2 * REGION, REGION)));
+        assert_eq!(allocator.shrink(), None);
+
+        allocator.deallocate(regions[0] as *mut u8, REGION, REGION);
+        assert_eq!(allocator.shrink(), Some((base + REGION, REGION)));
+        assert_eq!(allocator.shrink(), None);
+    }
+
+    #[test]
+    fn stats() {
+        let (_memory, mut allocator) = heap(2);
+        let ptr = allocator.allocate(3 * Block::SIZE, 1).unwrap();
+        let other = allocator.allocate(Block::SIZE, 1).unwrap();
+        allocator.deallocate(ptr, 3 * Block::SIZE, 1);
+
+        let stats = allocator.stats();
+        assert_eq!(stats.size, 2 * REGION);
+        assert_eq!(stats.used, Block::SIZE);
+        assert_eq!(stats.high_water, 5 * Block::SIZE);
+        assert_eq!(stats.allocations, 2);
+        assert_eq!(stats.outstanding, 1);
+        assert_eq!(stats.largest_free(), REGION);
+        assert_eq!(stats.free_blocks[BuddyAllocator::ORDER], 1);
+        assert_eq!(stats.free_blocks[0], 1);
+
+        allocator.deallocate(other, Block::SIZE, 1);
+        assert_eq!(allocator.stats().fragmentation(), 50);
+    }
+
+    #[test]
+    fn random_allocations_coalesce() {
+        let regions = 4;
+        let (_memory, mut allocator) = heap(regions);
+        let mut rng = Rng::new();
+        let mut allocations = Vec::new();
+
+        for _ in 0..20000 {
+            if allocations.is_empty() || rng.below(3) != 0 {
+                let size = 1 + rng.below(16 * Block::SIZE);
+                let align = 1 << rng.below(16);
+                if let Ok(ptr) = allocator.allocate(size, align) {
+                    assert_eq!(ptr as usize % align, 0);
+                    assert_disjoint(ptr, size, &allocations);
+                    allocations.push((ptr, size, align));
+                }
+            } else {
+                let index = rng.below(allocations.len());
+                let (ptr, size, align) = allocations.swap_remove(index);
+                allocator.deallocate(ptr, size, align);
+            }
+        }
+
+        for (ptr, size, align) in allocations.drain(..) {
+            allocator.deallocate(ptr, size, align);
+        }
+
+        // Everything has to be merged back into whole regions.
+        assert_eq!(allocator.free(), regions * REGION);
+        for _ in 0..regions {
+            assert!(allocator.allocate(REGION, 1).is_ok());
+        }
+    }
+
+    #[test]
+    fn zero_clears_whole_range() {
+        let (_memory, mut allocator) = heap(1);
+        let ptr = allocator.allocate(3 * Block::SIZE, 1).unwrap();
+        for i in 0..4 * Block::SIZE {
+            unsafe { *ptr.offset(i as isize) = 0xff };
+        }
+
+        BuddyAllocator::zero(ptr, 3 * Block::SIZE);
+        for i in 0..3 * Block::SIZE {
+            assert_eq!(unsafe { *ptr.offset(i as isize) }, 0);
+        }
+        assert_eq!(unsafe { *ptr.offset(3 * Block::SIZE as isize) }, 0xff);
+    }
+
+    #[test]
+    fn reallocate_inplace_grows_into_free_buddy() {
+        let (_memory, mut allocator) = heap(1);
+        let ptr = allocator.allocate(Block::SIZE, 1).unwrap();
+
+        assert!(allocator.reallocate_inplace(ptr, Block::SIZE, 4 * Block::SIZE, 1));
+        assert_eq!(allocator.free(), REGION - 4 * Block::SIZE);
+
+        let other = allocator.allocate(Block::SIZE, 1).unwrap() as usize;
+        assert!(other >= ptr as usize + 4 * Block::SIZE);
+    }
+
+    #[test]
+    fn reallocate_inplace_fails_if_buddy_is_used() {
+        let (_memory, mut allocator) = heap(1);
+        let ptr = allocator.allocate(Block::SIZE, 1).unwrap();
+        let buddy = allocator.allocate(Block::SIZE, 1).unwrap();
+        assert_eq!(buddy as usize, ptr as usize + Block::SIZE);
+
+        assert!(!allocator.reallocate_inplace(ptr, Block::SIZE, 2 * Block::SIZE, 1));
+        assert_eq!(allocator.free(), REGION - 2 * Block::SIZE);
+    }
+
+    #[test]
+    fn reallocate_inplace_fails_for_right_half() {
+        let (_memory, mut allocator) = heap(1);
+        let left = allocator.allocate(Block::SIZE, 1).unwrap();
+        let right = allocator.allocate(Block::SIZE, 1).unwrap();
+        allocator.deallocate(left, Block::SIZE, 1);
+
+        assert!(!allocator.reallocate_inplace(right, Block::SIZE, 2 * Block::SIZE, 1));
+    }
+
+    #[test]
+    fn reallocate_inplace_shrinks() {
+        let (_memory, mut allocator) = heap(1);
+        let ptr = allocator.allocate(4 * Block::SIZE, 1).unwrap();
+
+        assert!(allocator.reallocate_inplace(ptr, 4 * Block::SIZE, Block::SIZE, 1));
+        assert_eq!(allocator.free(), REGION - Block::SIZE);
+
+        let other = allocator.allocate(Block::SIZE, 1).unwrap();
+        assert_eq!(other as usize, ptr as usize + Block::SIZE);
+
+        allocator.deallocate(other, Block::SIZE, 1);
+        allocator.deallocate(ptr, Block::SIZE, 1);
+        assert_eq!(allocator.free(), REGION);
+        assert!(allocator.allocate(REGION, 1).is_ok());
+    }
+
+    #[test]
+    fn reallocate_keeps_objects_in_their_cache() {
+        let (_memory, mut allocator) = heap(1);
+        let mut slab = SlabAllocator::new();
+        let caches = (SlabAllocator::cache(12, 4), SlabAllocator::cache(10, 4));
+        let ptr = slab.allocate(caches.0.unwrap(), &mut allocator).unwrap();
+
+        assert_eq!(reallocate_inplace(&mut allocator, ptr, 12, 10, 4, caches), 16);
+    }
+
+    #[test]
+    fn reallocate_moves_objects_to_smaller_cache() {
+        let (_memory, mut allocator) = heap(1);
+        let mut slab = SlabAllocator::new();
+        let caches = (SlabAllocator::cache(16, 8), SlabAllocator::cache(8, 8));
+        let ptr = slab.allocate(caches.0.unwrap(), &mut allocator).unwrap();
+
+        assert!(reallocate_inplace(&mut allocator, ptr, 16, 8, 8, caches) < 8);
+    }
+
+    #[test]
+    fn reallocate_moves_blocks_into_cache() {
+        let (_memory, mut allocator) = heap(1);
+        let ptr = allocator.allocate(3000, 8).unwrap();
+        let free = allocator.free();
+        let caches = (SlabAllocator::cache(3000, 8), SlabAllocator::cache(1000, 8));
+        assert_eq!(caches.0, None);
+
+        assert!(reallocate_inplace(&mut allocator, ptr, 3000, 1000, 8, caches) < 1000);
+        assert_eq!(allocator.free(), free);
+    }
+}
+
+#[cfg(test)]
+mod bench {
+    extern crate test;
+
+    use self::test::Bencher;
+    use super::{Block, REGION};
+    use super::tests::heap;
+
+    #[bench]
+    fn allocate_split(b: &mut Bencher) {
+        let (_memory, mut allocator) = heap(1);
+
+        // Every allocation splits a whole region, and every free merges it back.
+        b.iter(|| {
+            let ptr = allocator.allocate(Block::SIZE, 1).unwrap();
+            allocator.deallocate(ptr, Block::SIZE, 1);
+        });
+    }
+
+    #[bench]
+    fn allocate_fragmented(b: &mut Bencher) {
+        let (_memory, mut allocator) = heap(4);
+
+        // Keep every other block in use, so none of the free blocks can be merged.
+        let blocks: Vec<_> = (0..4 * REGION / Block::SIZE)
+            .map(|_| allocator.allocate(Block::SIZE, 1).unwrap())
+            .collect();
+        for (_, &ptr) in blocks.iter().enumerate().filter(|&(i, _)| i % 2 == 0) {
+            allocator.deallocate(ptr, Block::SIZE, 1);
+        }
+
+        b.iter(|| {
+            let ptr = allocator.allocate(Block::SIZE, 1).unwrap();
+            allocator.deallocate(ptr, Block::SIZE, 1);
+        });
+    }
 }
@@ -0,0 +1,364 @@
+//! A slab allocator in front of the buddy allocator. Requests of up to 2 KiB are served from
+//! caches of equally sized objects, which are carved out of slabs taken from the buddy
+//! allocator, so small allocations do not take up a whole block each.
+
+use core::{cmp, fmt, mem};
+use super::{AllocError, Block, BuddyAllocator};
+
+/// The size of the objects in the smallest cache.
+const MIN: usize = 8;
+/// The amount of caches, each holding objects twice as large as the one before.
+pub const CACHES: usize = 9;
+/// The amount of objects a slab should hold at least, unless a single block holds more.
+const OBJECTS: usize = 8;
+
+pub struct SlabAllocator {
+    caches: [Cache; CACHES],
+}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        SlabAllocator {
+            caches: [Cache::new(MIN),
+                     Cache::new(MIN << 1),
+                     Cache::new(MIN << 2),
+                     Cache::new(MIN << 3),
+                     Cache::new(MIN << 4),
+                     Cache::new(MIN << 5),
+                     Cache::new(MIN << 6),
+                     Cache::new(MIN << 7),
+                     Cache::new(MIN << 8)],
+        }
+    }
+
+    /// Returns the cache that serves requests of the given size and alignment, if the request
+    /// is small enough.
+    ///
+    /// Objects are aligned to their size, so the alignment only has to fit into the cache.
+    pub fn cache(size: usize, align: usize) -> Option<usize> {
+        let size = cmp::max(size, align);
+        (0..CACHES).find(|&cache| size <= SlabAllocator::size(cache))
+    }
+
+    /// Returns the size of the objects in the given cache.
+    pub fn size(cache: usize) -> usize {
+        MIN << cache
+    }
+
+    pub fn allocate(&mut self,
+                    cache: usize,
+                    heap: &mut BuddyAllocator)
+                    -> Result<*mut u8, AllocError> {
+        self.caches[cache].allocate(heap)
+    }
+
+    pub fn deallocate(&mut self, ptr: *mut u8, cache: usize, heap: &mut BuddyAllocator) {
+        self.caches[cache].deallocate(ptr, heap)
+    }
+
+    /// Gives the spare slabs of all caches back to the heap and returns whether there were any.
+    pub fn reclaim(&mut self, heap: &mut BuddyAllocator) -> bool {
+        let mut reclaimed = false;
+        for cache in self.caches.iter_mut() {
+            reclaimed |= cache.reclaim(heap);
+        }
+        reclaimed
+    }
+
+    pub fn stats(&self) -> [CacheStats; CACHES] {
+        let mut stats = [CacheStats::default(); CACHES];
+        for (stats, cache) in stats.iter_mut().zip(self.caches.iter()) {
+            *stats = cache.stats;
+        }
+        stats
+    }
+}
+
+/// Usage statistics of a single cache.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CacheStats {
+    /// The size of the objects in the cache.
+    pub size: usize,
+    /// The amount of slabs taken from the heap, including the spare one.
+    pub slabs: usize,
+    /// The amount of objects that are handed out.
+    pub used: usize,
+    /// The amount of objects all slabs hold together.
+    pub capacity: usize,
+    /// The amount of allocations served since boot.
+    pub allocations: usize,
+    /// The amount of objects returned since boot.
+    pub deallocations: usize,
+}
+
+impl fmt::Display for CacheStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{:4} B: {}/{} objects in {} slabs, {} allocations, {} frees",
+               self.size,
+               self.used,
+               self.capacity,
+               self.slabs,
+               self.allocations,
+               self.deallocations)
+    }
+}
+
+/// The header at the start of every slab.
+#[derive(Clone, Copy)]
+struct Slab {
+    /// The address of the first free object, or 0 if the slab is full. Free objects hold the
+    /// address of the next one.
+    free: usize,
+    /// The amount of objects that are handed out.
+    used: usize,
+    /// The neighbours on the list of partially used slabs.
+    prev: usize,
+    next: usize,
+}
+
+/// A cache of objects of a single size.
+struct Cache {
+    /// The address of the first slab that still has free objects, or 0 if there is none.
+    partial: usize,
+    /// An empty slab kept around so a cache does not take and return a slab over and over, or
+    /// 0 if there is none.
+    spare: usize,
+    stats: CacheStats,
+}
+
+impl Cache {
+    const fn new(size: usize) -> Cache {
+        Cache {
+            partial: 0,
+            spare: 0,
+            stats: CacheStats {
+                size: size,
+                slabs: 0,
+                used: 0,
+                capacity: 0,
+                allocations: 0,
+                deallocations: 0,
+            },
+        }
+    }
+
+    fn allocate(&mut self, heap: &mut BuddyAllocator) -> Result<*mut u8, AllocError> {
+        if self.partial == 0 {
+            let slab = self.grow(heap)?;
+            self.push(slab);
+        }
+
+        let addr = self.partial;
+        let (object, full) = {
+            let slab = unsafe { &mut *(addr as *mut Slab) };
+            let object = slab.free;
+            slab.free = unsafe { *(object as *const usize) };
+            slab.used += 1;
+            (object, slab.free == 0)
+        };
+        if full {
+            self.remove(addr);
+        }
+
+        self.stats.used += 1;
+        self.stats.allocations += 1;
+        Ok(object as *mut u8)
+    }
+
+    fn deallocate(&mut self, ptr: *mut u8, heap: &mut BuddyAllocator) {
+        let addr = (ptr as usize) & !(self.slab_size() - 1);
+        let (full, empty) = {
+            let slab = unsafe { &mut *(addr as *mut Slab) };
+            let full = slab.free == 0;
+
+            unsafe { *(ptr as *mut usize) = slab.free };
+            slab.free = ptr as usize;
+            slab.used -= 1;
+            (full, slab.used == 0)
+        };
+
+        self.stats.used -= 1;
+        self.stats.deallocations += 1;
+
+        if full {
+            self.push(addr);
+        }
+        if empty {
+            self.remove(addr);
+            self.release(addr, heap);
+        }
+    }
+
+    /// Returns a slab with all objects free, either the spare one or a new one from the heap.
+    fn grow(&mut self, heap: &mut BuddyAllocator) -> Result<usize, AllocError> {
+        if self.spare != 0 {
+            let slab = self.spare;
+            self.spare = 0;
+            return Ok(slab);
+        }
+
+        let size = self.slab_size();
+        let addr = heap.allocate(size, size)? as usize;
+
+        // Thread all objects onto the free list, starting with the lowest address.
+        let first = self.first();
+        let objects = (size - first) / self.stats.size;
+        let mut free = 0;
+        for i in (0..objects).rev() {
+            let object = addr + first + i * self.stats.size;
+            unsafe { *(object as *mut usize) = free };
+            free = object;
+        }
+        unsafe {
+            *(addr as *mut Slab) = Slab {
+                free: free,
+                used: 0,
+                prev: 0,
+                next: 0,
+            };
+        }
+
+        self.stats.slabs += 1;
+        self.stats.capacity += objects;
+        Ok(addr)
+    }
+
+    /// Keeps an empty slab as the spare one, or gives it back to the heap if there already is
+    /// one.
+    fn release(&mut self, slab: usize, heap: &mut BuddyAllocator) {
+        if self.spare == 0 {
+            self.spare = slab;
+        } else {
+            self.free(slab, heap);
+        }
+    }
+
+    fn reclaim(&mut self, heap: &mut BuddyAllocator) -> bool {
+        if self.spare == 0 {
+            return false;
+        }
+
+        let slab = self.spare;
+        self.spare = 0;
+        self.free(slab, heap);
+        true
+    }
+
+    fn free(&mut self, slab: usize, heap: &mut BuddyAllocator) {
+        let size = self.slab_size();
+        heap.deallocate(slab as *mut u8, size, size);
+
+        self.stats.slabs -= 1;
+        self.stats.capacity -= (size - self.first()) / self.stats.size;
+    }
+
+    /// Puts the slab at the front of the list of partially used slabs.
+    fn push(&mut self, addr: usize) {
+        let next = self.partial;
+        unsafe {
+            let slab = &mut *(addr as *mut Slab);
+            slab.prev = 0;
+            slab.next = next;
+            if next != 0 {
+                (*(next as *mut Slab)).prev = addr;
+            }
+        }
+        self.partial = addr;
+    }
+
+    /// Takes the slab off the list of partially used slabs.
+    fn remove(&mut self, addr: usize) {
+        let slab = unsafe { *(addr as *const Slab) };
+        if slab.prev == 0 {
+            self.partial = slab.next;
+        } else {
+            unsafe { (*(slab.prev as *mut Slab)).next = slab.next };
+        }
+        if slab.next != 0 {
+            unsafe { (*(slab.next as *mut Slab)).prev = slab.prev };
+        }
+    }
+
+    /// Returns the size of the slabs of this cache. Slabs are aligned to their size, so the
+    /// header of a slab can be found from any of its objects.
+    fn slab_size(&self) -> usize {
+        cmp::max(Block::SIZE, self.stats.size * OBJECTS)
+    }
+
+    /// Returns the offset of the first object in a slab, which follows the header and keeps
+    /// objects aligned to their size.
+    fn first(&self) -> usize {
+        cmp::max(mem::size_of::<Slab>(), self.stats.size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SlabAllocator, CACHES};
+    use super::super::REGION;
+    use super::super::tests::{assert_disjoint, heap, Rng};
+
+    #[test]
+    fn cache() {
+        assert_eq!(SlabAllocator::cache(1, 1), Some(0));
+        assert_eq!(SlabAllocator::cache(8, 1), Some(0));
+        assert_eq!(SlabAllocator::cache(9, 1), Some(1));
+        assert_eq!(SlabAllocator::cache(8, 64), Some(3));
+        assert_eq!(SlabAllocator::cache(2048, 1), Some(CACHES - 1));
+        assert_eq!(SlabAllocator::cache(2049, 1), None);
+    }
+
+    #[test]
+    fn empty_slabs_are_released() {
+        let (_memory, mut heap) = heap(1);
+        let mut slab = SlabAllocator::new();
+
+        let objects: Vec<_> = (0..1000).map(|_| slab.allocate(0, &mut heap).unwrap()).collect();
+        assert!(slab.stats()[0].slabs > 1);
+        for &ptr in &objects {
+            slab.deallocate(ptr, 0, &mut heap);
+        }
+
+        // Only the spare slab is kept, until it is reclaimed.
+        assert_eq!(slab.stats()[0].slabs, 1);
+        assert_eq!(slab.stats()[0].used, 0);
+        assert!(slab.reclaim(&mut heap));
+        assert!(!slab.reclaim(&mut heap));
+        assert_eq!(heap.free(), REGION);
+    }
+
+    #[test]
+    fn random_allocations() {
+        let (_memory, mut heap) = heap(2);
+        let mut slab = SlabAllocator::new();
+        let mut rng = Rng::new();
+        let mut allocations = Vec::new();
+
+        for _ in 0..20000 {
+            if allocations.is_empty() || rng.below(2) != 0 {
+                let size = 1 + rng.below(2048);
+                let align = 1 << rng.below(8);
+                let cache = SlabAllocator::cache(size, align).unwrap();
+                let ptr = slab.allocate(cache, &mut heap).unwrap();
+                assert_eq!(ptr as usize % align, 0);
+                assert_disjoint(ptr, size, &allocations);
+                allocations.push((ptr, size, cache));
+            } else {
+                let index = rng.below(allocations.len());
+                let (ptr, _, cache) = allocations.swap_remove(index);
+                slab.deallocate(ptr, cache, &mut heap);
+            }
+        }
+
+        for (ptr, _, cache) in allocations.drain(..) {
+            slab.deallocate(ptr, cache, &mut heap);
+        }
+        for stats in slab.stats().iter() {
+            assert_eq!(stats.used, 0);
+            assert_eq!(stats.allocations, stats.deallocations);
+        }
+        slab.reclaim(&mut heap);
+        assert_eq!(heap.free(), 2 * REGION);
+    }
+}
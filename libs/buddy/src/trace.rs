@@ -0,0 +1,105 @@
+//! Records the call site of every allocation that has not been freed yet, so leaks can be
+//! tracked down.
+
+use spin::Mutex;
+
+/// The amount of allocations that can be tracked at once.
+const ENTRIES: usize = 1024;
+
+static TRACER: Mutex<Tracer> = Mutex::new(Tracer::new());
+
+/// An allocation that has not been freed yet.
+#[derive(Debug, Clone, Copy)]
+pub struct Allocation {
+    pub ptr: usize,
+    pub size: usize,
+    /// The address the call into the allocator returns to.
+    pub site: usize,
+}
+
+struct Tracer {
+    /// The tracked allocations, where a null pointer marks an unused entry.
+    entries: [Allocation; ENTRIES],
+    /// The amount of allocations that did not fit into the table.
+    untracked: usize,
+}
+
+impl Tracer {
+    const fn new() -> Tracer {
+        Tracer {
+            entries: [Allocation {
+                ptr: 0,
+                size: 0,
+                site: 0,
+            }; ENTRIES],
+            untracked: 0,
+        }
+    }
+
+    fn find(&mut self, ptr: usize) -> Option<&mut Allocation> {
+        self.entries.iter_mut().find(|entry| entry.ptr == ptr)
+    }
+}
+
+extern "C" {
+    /// Returns the return address of the function the call ends up in. Unlike reading it through
+    /// the frame pointer, this also works in builds without frame pointers.
+    #[link_name = "llvm.returnaddress"]
+    fn return_address(level: i32) -> *const u8;
+}
+
+/// Returns the address the current function returns to. The function has to be inlined into the
+/// allocator entry point whose caller is wanted.
+#[inline(always)]
+pub fn caller() -> usize {
+    unsafe { return_address(0) as usize }
+}
+
+pub fn record(ptr: *mut u8, size: usize, site: usize) {
+    if ptr.is_null() {
+        return;
+    }
+
+    let mut tracer = TRACER.lock();
+    let tracked = match tracer.find(0) {
+        Some(entry) => {
+            *entry = Allocation {
+                ptr: ptr as usize,
+                size: size,
+                site: site,
+            };
+            true
+        }
+        None => false,
+    };
+    if !tracked {
+        tracer.untracked += 1;
+    }
+}
+
+pub fn resize(ptr: *mut u8, size: usize) {
+    if let Some(entry) = TRACER.lock().find(ptr as usize) {
+        entry.size = size;
+    }
+}
+
+pub fn forget(ptr: *mut u8) {
+    if let Some(entry) = TRACER.lock().find(ptr as usize) {
+        entry.ptr = 0;
+    }
+}
+
+/// Calls `f` with every allocation that has not been freed yet, and returns the amount of
+/// allocations that could not be tracked because the table was full.
+///
+/// The closure must not allocate.
+pub fn outstanding<F>(mut f: F) -> usize
+where
+    F: FnMut(&Allocation),
+{
+    let tracer = TRACER.lock();
+    for entry in tracer.entries.iter().filter(|entry| entry.ptr != 0) {
+        f(entry);
+    }
+    tracer.untracked
+}
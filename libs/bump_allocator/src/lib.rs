@@ -1,9 +1,11 @@
 #![feature(allocator)]
 #![feature(const_fn)]
 
-#![allocator]
-#![no_std]
+#![cfg_attr(not(test), allocator)]
+#![cfg_attr(not(test), no_std)]
 
+#[cfg(test)]
+extern crate core;
 extern crate spin;
 
 use spin::Mutex;
@@ -18,6 +20,19 @@ struct BumpAllocator {
     start: usize,
     size: usize,
     next: usize,
+    allocations: usize,
+}
+
+/// A snapshot of the usage of the heap. Memory is never given back, so the amount of bytes in
+/// use is also the high-water mark.
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    /// The size of the heap in bytes.
+    pub size: usize,
+    /// The amount of bytes handed out, including padding for alignment.
+    pub used: usize,
+    /// The amount of allocations served since boot.
+    pub allocations: usize,
 }
 
 impl BumpAllocator {
@@ -26,6 +41,15 @@ impl BumpAllocator {
             start: start,
             size: size,
             next: start,
+            allocations: 0,
+        }
+    }
+
+    fn stats(&self) -> HeapStats {
+        HeapStats {
+            size: self.size,
+            used: self.next - self.start,
+            allocations: self.allocations,
         }
     }
 
@@ -35,6 +59,7 @@ impl BumpAllocator {
 
         if end <= self.start + self.size {
             self.next = end;
+            self.allocations += 1;
             Some(start as *mut u8)
         } else {
             None
@@ -42,6 +67,11 @@ impl BumpAllocator {
     }
 }
 
+/// Returns the usage statistics of the heap.
+pub fn heap_stats() -> HeapStats {
+    ALLOCATOR.lock().stats()
+}
+
 pub fn align_down(addr: usize, align: usize) -> usize {
     if align.is_power_of_two() {
         addr & !(align - 1)
@@ -56,6 +86,7 @@ pub fn align_up(addr: usize, align: usize) -> usize {
     align_down(addr + align - 1, align)
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
     ALLOCATOR
@@ -64,14 +95,17 @@ pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
         .expect("Out of memory")
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn __rust_deallocate(_ptr: *mut u8, _size: usize, _align: usize) {}
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn __rust_usable_size(size: usize, _align: usize) -> usize {
     size
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn __rust_reallocate_inplace(
     _ptr: *mut u8,
@@ -82,6 +116,7 @@ pub extern "C" fn __rust_reallocate_inplace(
     size
 }
 
+#[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn __rust_reallocate(
     ptr: *mut u8,
@@ -96,3 +131,56 @@ pub extern "C" fn __rust_reallocate(
     __rust_deallocate(ptr, size, align);
     new_ptr
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{align_down, align_up, BumpAllocator};
+
+    #[test]
+    fn align() {
+        assert_eq!(align_down(0x1234, 0x1000), 0x1000);
+        assert_eq!(align_down(0x1234, 0), 0x1234);
+        assert_eq!(align_up(0x1234, 0x1000), 0x2000);
+        assert_eq!(align_up(0x2000, 0x1000), 0x2000);
+        assert_eq!(align_up(0x1235, 1), 0x1235);
+    }
+
+    #[test]
+    #[should_panic]
+    fn align_not_power_of_two() {
+        align_down(0x1234, 3);
+    }
+
+    #[test]
+    fn allocate() {
+        let mut allocator = BumpAllocator::new(0x1000, 0x100);
+
+        assert_eq!(allocator.allocate(0x10, 1), Some(0x1000 as *mut u8));
+        assert_eq!(allocator.allocate(0x8, 0x40), Some(0x1040 as *mut u8));
+        assert_eq!(allocator.allocate(0x1, 1), Some(0x1048 as *mut u8));
+        assert_eq!(allocator.next, 0x1049);
+
+        let stats = allocator.stats();
+        assert_eq!(stats.used, 0x49);
+        assert_eq!(stats.allocations, 3);
+    }
+
+    #[test]
+    fn allocate_exhausted() {
+        let mut allocator = BumpAllocator::new(0x1000, 0x100);
+
+        assert_eq!(allocator.allocate(0x100, 1), Some(0x1000 as *mut u8));
+        assert_eq!(allocator.allocate(0x1, 1), None);
+
+        // A failed request must not use up any memory.
+        let mut allocator = BumpAllocator::new(0x1000, 0x100);
+        assert_eq!(allocator.allocate(0x101, 1), None);
+        assert_eq!(allocator.allocate(0x100, 1), Some(0x1000 as *mut u8));
+    }
+
+    #[test]
+    fn allocate_overflow() {
+        let mut allocator = BumpAllocator::new(0x1000, 0x100);
+        assert_eq!(allocator.allocate(usize::max_value(), 1), None);
+    }
+}
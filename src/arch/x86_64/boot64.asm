@@ -6,8 +6,6 @@ extern kmain
 _start64:
 
     call _zero_segments
-    call _enable_nx
-    call _enable_wp
 
     call kmain
 
@@ -20,19 +18,6 @@ _start64:
     mov [0xb8010], rax
     hlt
 
-_enable_nx:             ; Enable the No-Execute feature 
-    mov ecx, 0xC0000080
-    rdmsr
-    or eax, 0x800
-    wrmsr
-    ret
-
-_enable_wp:             ; Enable the Write Protect feature 
-    mov rcx, cr0
-    or rcx, 0x10000
-    mov cr0, rcx
-    ret
-
 _zero_segments:
     mov ax, 0
     mov ss, ax
@@ -1,7 +1,7 @@
-use memory::MemoryController;
+use memory;
 use spin::Once;
 use util::log::{Level, Logger};
-use x86_64::structures::idt::{ExceptionStackFrame, Idt};
+use x86_64::structures::idt::{ExceptionStackFrame, Idt, PageFaultErrorCode};
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::gdt::SegmentSelector;
 use x86_64::instructions::segmentation::set_cs;
@@ -17,6 +17,8 @@ lazy_static! {
         idt.general_protection_fault.set_handler_fn(gp_handler);
         unsafe {
             idt.double_fault.set_handler_fn(df_handler).set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
+            // Faults on lazily mapped stacks cannot be handled on the faulting stack.
+            idt.page_fault.set_handler_fn(pf_handler).set_stack_index(PAGE_FAULT_IST_INDEX as u16);
         }
 
         idt
@@ -27,16 +29,20 @@ static TSS: Once<TaskStateSegment> = Once::new();
 static GDT: Once<gdt::GlobalDescriptorTable> = Once::new();
 
 const DOUBLE_FAULT_IST_INDEX: usize = 0;
+const PAGE_FAULT_IST_INDEX: usize = 1;
 
-pub fn init(mcon: &mut MemoryController) {
+pub fn init() {
     use x86_64::VirtualAddress;
 
-    let df_stack = mcon.allocate_stack(4)
+    let df_stack = memory::with(|mcon| mcon.allocate_stack("double fault", 4))
         .expect("Could not allocate stack for double fault handler.");
+    let pf_stack = memory::with(|mcon| mcon.allocate_stack("page fault", 4))
+        .expect("Could not allocate stack for page fault handler.");
 
     let tss = TSS.call_once(|| {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX] = VirtualAddress(df_stack.top());
+        tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX] = VirtualAddress(pf_stack.top());
         tss
     });
 
@@ -77,3 +83,47 @@ extern "x86-interrupt" fn gp_handler(stack: &mut ExceptionStackFrame, code: u64)
     log!(Level::Warn, "Printing stack frame at point of exception:");
     log!(Level::Warn, "{:#?}", stack);
 }
+
+extern "x86-interrupt" fn pf_handler(stack: &mut ExceptionStackFrame, code: PageFaultErrorCode) {
+    use x86_64::registers::control_regs;
+
+    let addr = control_regs::cr2().0;
+    if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) &&
+        code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) &&
+        memory::handle_protection_fault(addr)
+    {
+        return;
+    }
+    if !code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) && memory::handle_page_fault(addr)
+    {
+        return;
+    }
+    if let Some(name) = memory::overflowed_stack(addr) {
+        log!(Level::Warn, "Printing stack frame at point of exception:");
+        log!(Level::Warn, "{:#?}", stack);
+        panic!(
+            "Stack overflow in stack {} at {:#x}, RIP {:#x}.",
+            name,
+            addr,
+            stack.instruction_pointer.0
+        );
+    }
+
+    log!(Level::Warn, "Caught exception: Page Fault");
+    log!(
+        Level::Warn,
+        "{} access to {:#x} ({:?})",
+        if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "Write"
+        } else if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "Instruction"
+        } else {
+            "Read"
+        },
+        addr,
+        code
+    );
+    log!(Level::Warn, "Printing stack frame at point of exception:");
+    log!(Level::Warn, "{:#?}", stack);
+    panic!("Encountered unhandled page fault exception.");
+}
@@ -2,7 +2,9 @@
 
 #![feature(abi_x86_interrupt)]
 #![feature(alloc)]
+#![feature(asm)]
 #![feature(associated_consts)]
+#![feature(compile_error)]
 #![feature(const_fn)]
 #![feature(lang_items)]
 #![feature(unique)]
@@ -13,7 +15,10 @@
 extern crate alloc;
 #[macro_use]
 extern crate bitflags;
+#[cfg(feature = "heap-buddy")]
 extern crate buddy;
+#[cfg(feature = "heap-bump")]
+extern crate bump_allocator;
 #[macro_use]
 extern crate lazy_static;
 extern crate multiboot2;
@@ -34,6 +39,10 @@ mod error;
 use core::fmt;
 use util::log::{Level, Logger};
 
+/// The amount of pages of the kernel stack. They are mapped right away, since a fault on a stack
+/// page cannot be handled while the memory controller is held.
+const KERNEL_STACK_PAGES: usize = 64;
+
 #[no_mangle]
 /// The kernel entry point.
 pub extern "C" fn kmain(mb_addr: usize) {
@@ -41,14 +50,55 @@ pub extern "C" fn kmain(mb_addr: usize) {
     let info = unsafe { multiboot2::load(mb_addr) };
 
     log!(Level::Info, "Initializing memory...");
-    let mut mcon = memory::init(&info);
+    memory::init(&info);
 
     log!(Level::Info, "Enabling interrupt handlers...");
-    interrupt::init(&mut mcon);
+    interrupt::init();
+
+    // Leave the small boot stack for a larger one.
+    let stack = memory::with(|mcon| mcon.allocate_stack("kernel", KERNEL_STACK_PAGES))
+        .expect("Could not allocate the kernel stack.");
+    unsafe { switch_stack(stack.top(), run) }
+}
 
+/// Continues booting on the kernel stack.
+fn run() -> ! {
+    log!(Level::Info, "Testing write protection...");
+    assert!(
+        memory::test_write_protect(),
+        "Writing to read-only data did not fault."
+    );
+
+    log!(Level::Info, "Testing demand paging...");
+    assert!(
+        memory::test_demand_paging(),
+        "A lazily mapped page did not read as zeros."
+    );
+
+    log!(Level::Info, "Testing copy-on-write...");
+    assert!(
+        memory::test_fork(),
+        "A fork of the address space shared a page after writing to it."
+    );
+
+    if cfg!(feature = "memory-dump") {
+        memory::dump(Level::Info);
+    }
+    memory::heap::dump_outstanding();
     panic!("Did not crash!");
 }
 
+/// Calls the given function on the stack with the given top. The current stack is abandoned.
+unsafe fn switch_stack(top: usize, f: fn() -> !) -> ! {
+    asm!("mov rsp, $0
+          call $1"
+         :
+         : "r"(top), "r"(f as usize)
+         :
+         : "intel", "volatile");
+    unreachable!();
+}
+
 #[lang = "eh_personality"]
 /// Not too sure.
 extern "C" fn eh_personality() {}
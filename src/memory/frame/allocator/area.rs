@@ -1,12 +1,26 @@
 use memory::frame::{Allocator, Frame};
 use multiboot2::{MemoryArea, MemoryAreaIter};
 
+/// The amount of contiguous runs of frames that can be recorded.
+const RUNS: usize = 16;
+/// The amount of freed frames that can be held for reuse.
+const FREED: usize = 16;
+
+/// A simple allocator that hands out the frames of the available memory areas in order. It
+/// keeps a record of every frame it gave out, so a later allocator can take over its state.
 pub struct AreaAllocator {
     area: Option<&'static MemoryArea>,
     areas: MemoryAreaIter,
     kernel: (Frame, Frame),
     multiboot: (Frame, Frame),
     next: Frame,
+    /// The runs of frames handed out so far, as the id of the first frame and the id after the
+    /// last one.
+    runs: [(usize, usize); RUNS],
+    used: usize,
+    /// The ids of frames that were handed out and freed again.
+    freed: [usize; FREED],
+    free: usize,
 }
 
 impl AreaAllocator {
@@ -24,11 +38,24 @@ impl AreaAllocator {
                 Frame::containing(multiboot.0),
                 Frame::containing(multiboot.1),
             ),
+            runs: [(0, 0); RUNS],
+            used: 0,
+            freed: [0; FREED],
+            free: 0,
         };
         allocator.next();
         allocator
     }
 
+    /// Returns an iterator over every frame that has been handed out and not freed since.
+    pub fn frames(&self) -> AreaFrames {
+        AreaFrames {
+            allocator: self,
+            run: 0,
+            next: self.runs[0].0,
+        }
+    }
+
     fn next(&mut self) {
         self.area = self.areas
             .clone()
@@ -45,10 +72,31 @@ impl AreaAllocator {
             }
         }
     }
+
+    /// Adds the frame to the record of handed out frames.
+    fn record(&mut self, frame: &Frame) {
+        if self.used > 0 && self.runs[self.used - 1].1 == frame.id() {
+            self.runs[self.used - 1].1 += 1;
+        } else {
+            assert!(self.used < RUNS, "Too many runs of frames to record.");
+            self.runs[self.used] = (frame.id(), frame.id() + 1);
+            self.used += 1;
+        }
+    }
+
+    fn is_freed(&self, frame: &Frame) -> bool {
+        self.freed[..self.free].contains(&frame.id())
+    }
 }
 
 impl Allocator for AreaAllocator {
     fn allocate(&mut self) -> Option<Frame> {
+        // Hand out freed frames first, they are still part of the record.
+        if self.free > 0 {
+            self.free -= 1;
+            return Some(Frame { id: self.freed[self.free] });
+        }
+
         if let Some(area) = self.area {
             let frame = self.next.clone();
 
@@ -65,6 +113,7 @@ impl Allocator for AreaAllocator {
                 self.next = Frame { id: self.multiboot.1.id + 1 };
             } else {
                 self.next.id += 1;
+                self.record(&frame);
                 return Some(frame);
             }
             self.allocate()
@@ -73,7 +122,53 @@ impl Allocator for AreaAllocator {
         }
     }
 
-    fn deallocate(&mut self, _: Frame) {
-        unimplemented!()
+    fn deallocate(&mut self, frame: Frame) {
+        assert!(
+            self.runs[..self.used]
+                .iter()
+                .any(|&(start, end)| frame.id() >= start && frame.id() < end),
+            "Frame {:#x} was not allocated.",
+            frame.base()
+        );
+        assert!(
+            !self.is_freed(&frame),
+            "Frame {:#x} was already freed.",
+            frame.base()
+        );
+        assert!(self.free < FREED, "Too many freed frames to hold.");
+
+        self.freed[self.free] = frame.id();
+        self.free += 1;
+    }
+}
+
+/// Iterates over the frames an [`AreaAllocator`](struct.AreaAllocator.html) handed out.
+pub struct AreaFrames<'a> {
+    allocator: &'a AreaAllocator,
+    run: usize,
+    next: usize,
+}
+
+impl<'a> Iterator for AreaFrames<'a> {
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        while self.run < self.allocator.used {
+            let (_, end) = self.allocator.runs[self.run];
+            if self.next >= end {
+                self.run += 1;
+                if self.run < self.allocator.used {
+                    self.next = self.allocator.runs[self.run].0;
+                }
+                continue;
+            }
+
+            let frame = Frame { id: self.next };
+            self.next += 1;
+            if !self.allocator.is_freed(&frame) {
+                return Some(frame);
+            }
+        }
+        None
     }
 }
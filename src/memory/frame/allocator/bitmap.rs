@@ -1,65 +1,249 @@
-use core::mem;
-use memory::frame::{Allocator, Frame};
+use core::{cmp, mem, ptr};
+use memory::frame::{Allocator, Frame, Zone};
+use multiboot2::MemoryAreaIter;
+use super::area::AreaAllocator;
 
 pub struct BitmapAllocator {
     amount: usize,
     base: usize,
-    last: usize,
+    /// The address of the reference counts, one `u16` per frame, stored right after the bitmaps.
+    /// Used frames with a count of zero are reserved and never freed.
+    counts: usize,
+    /// The index of the first bitmap with free frames, per zone.
+    last: [usize; Zone::COUNT],
+    /// The amount of free frames, per zone.
+    free: [usize; Zone::COUNT],
 }
 
 impl BitmapAllocator {
-    pub fn new<A>(total_size: usize, allocator: &mut A) -> BitmapAllocator
-    where
-        A: Allocator,
-    {
-        // First determine the amount of bitmaps needed, to address the whole memory.
-        // Each Bitmap can hold mem::size_of::<usize> * 8 frames, since a frame is simply
-        // represented by a bit.
-        let amount = total_size / (Frame::SIZE * mem::size_of::<usize>() * 8);
+    /// Creates a new allocator for the available memory areas, which takes over from the given
+    /// `AreaAllocator`.
+    ///
+    /// Every frame starts out as used, and only frames that lie completely inside one of the
+    /// available areas are freed, so holes in the memory map are never handed out. The kernel
+    /// image, the multiboot information and every frame the area allocator handed out, including
+    /// the frames the bitmaps and reference counts are stored in, are reserved afterwards.
+    pub fn new(
+        areas: MemoryAreaIter,
+        kernel: (usize, usize),
+        multiboot: (usize, usize),
+        mut allocator: AreaAllocator,
+    ) -> BitmapAllocator {
+        // First determine the amount of bitmaps needed, to address the memory up to the end of
+        // the highest area. Each Bitmap can hold mem::size_of::<usize> * 8 frames, since a frame
+        // is simply represented by a bit.
+        let end = areas
+            .clone()
+            .map(|area| (area.base_addr + area.length) as usize)
+            .max()
+            .expect("No available memory areas.");
+        let frames = (end + Frame::SIZE - 1) / Frame::SIZE;
+        let bits = mem::size_of::<usize>() * 8;
+        let amount = (frames + bits - 1) / bits;
 
-        // Allocate a frame to save the bitmaps in memory.
-        let n = (amount * mem::size_of::<usize>()) / Frame::SIZE + 1;
+        // Allocate the frames to save the bitmaps and the reference counts in memory.
+        let size = amount * mem::size_of::<usize>() + amount * bits * mem::size_of::<u16>();
+        let n = (size + Frame::SIZE - 1) / Frame::SIZE;
         let frame = allocator
             .allocate()
             .expect("Could not allocate frame for bitmaps.");
-        for _ in 1..n {
-            allocator
+        for i in 1..n {
+            let next = allocator
                 .allocate()
                 .expect("Could not allocate frame for bitmaps.");
+            assert!(
+                next.id() == frame.id() + i,
+                "Frames for bitmaps are not contiguous."
+            );
         }
 
-        // Now zero the memory space the bitmaps will occupy.
+        // Now mark every frame as used, without any references.
         for i in 0..amount {
-            Bitmap::from(frame.base(), i).zero();
+            Bitmap::from(frame.base(), i).fill();
+        }
+        let counts = frame.base() + amount * mem::size_of::<usize>();
+        unsafe {
+            ptr::write_bytes(counts as *mut u16, 0, amount * bits);
         }
 
-        let allocator = BitmapAllocator {
+        let mut bitmaps = BitmapAllocator {
             amount: amount,
             base: frame.base(),
-            last: 0,
+            counts: counts,
+            last: [0; Zone::COUNT],
+            free: [0; Zone::COUNT],
         };
 
-        allocator.mark(allocator.base, amount * mem::size_of::<usize>());
+        // Free the frames that lie completely inside an available area.
+        for area in areas {
+            let start = area.base_addr as usize;
+            let end = (area.base_addr + area.length) as usize;
+            let first = (start + Frame::SIZE - 1) / Frame::SIZE;
+            let last = end / Frame::SIZE;
+            for id in first..last {
+                bitmaps.unmark_frame(&Frame { id: id });
+            }
+        }
+
+        // The first frame holds the real mode interrupt vector table and the BIOS data area.
+        bitmaps.mark_frame(&Frame { id: 0 });
+
+        bitmaps.mark(kernel.0, kernel.1);
+        bitmaps.mark(multiboot.0, multiboot.1);
+        for frame in allocator.frames() {
+            bitmaps.mark_frame(&frame);
+        }
+
+        bitmaps.count_free();
+        bitmaps
+    }
+
+    /// Returns the amount of free frames in the zone.
+    pub fn free(&self, zone: Zone) -> usize {
+        self.free[zone as usize]
+    }
+
+    /// Allocates a frame from the zone.
+    pub fn allocate_in(&mut self, zone: Zone) -> Option<Frame> {
+        let z = zone as usize;
+        if self.free[z] == 0 {
+            return None;
+        }
+
+        // Zones start and end on bitmap boundaries, so every bitmap belongs to a single zone.
+        let bits = mem::size_of::<usize>() * 8;
+        let (start, end) = self.bounds(zone);
+        for i in cmp::max(self.last[z], start / bits)..end / bits {
+            self.last[z] = i;
+            if let Some(offset) = Bitmap::from(self.base, i).next() {
+                self.free[z] -= 1;
+                let frame = Frame { id: i * bits + offset };
+                unsafe {
+                    *self.references_mut(frame.id()) = 1;
+                }
+                return Some(frame);
+            }
+        }
+        None
+    }
+
+    /// Allocates `count` physically contiguous frames from the zone and returns the first one.
+    /// The id of the first frame is a multiple of `align`, which has to be a power of two.
+    pub fn allocate_contiguous_in(
+        &mut self,
+        zone: Zone,
+        count: usize,
+        align: usize,
+    ) -> Option<Frame> {
+        assert!(align.is_power_of_two(), "Alignment must be a power of two.");
+        assert!(count > 0, "Cannot allocate zero frames.");
+
+        let z = zone as usize;
+        if self.free[z] < count {
+            return None;
+        }
+
+        let bits = mem::size_of::<usize>() * 8;
+        let base = self.base;
+        let (start, end) = self.bounds(zone);
+        let mut start = (start + align - 1) & !(align - 1);
+        while start + count <= end {
+            // Find the last used frame in the candidate range, and continue after it.
+            let used = Bitmap::words(start, start + count, |i, mask| {
+                let used = Bitmap::from(base, i).get() & mask;
+                if used != 0 {
+                    let highest = bits - 1 - used.leading_zeros() as usize;
+                    Some(i * bits + highest)
+                } else {
+                    None
+                }
+            });
+
+            match used {
+                Some(id) => start = (id + 1 + align - 1) & !(align - 1),
+                None => {
+                    Bitmap::words(start, start + count, |i, mask| {
+                        Bitmap::from(base, i).set_mask(mask);
+                        None::<()>
+                    });
+                    self.free[z] -= count;
+                    for id in start..start + count {
+                        unsafe {
+                            *self.references_mut(id) = 1;
+                        }
+                    }
+                    return Some(Frame { id: start });
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns a pointer to the reference count of the frame.
+    fn references_mut(&self, id: usize) -> *mut u16 {
+        assert!(
+            id < self.amount * mem::size_of::<usize>() * 8,
+            "Frame is out of range."
+        );
+        (self.counts + id * mem::size_of::<u16>()) as *mut u16
+    }
+
+    /// Marks a single frame as free.
+    fn release(&mut self, id: usize) {
+        let bits = mem::size_of::<usize>() * 8;
+        let frame = Frame { id: id };
+        Bitmap::containing(self.base, frame.base()).unset(Bitmap::offset(frame.base()));
 
-        // Mark the whole lower part of memory as used, so we won't write into something important.
-        allocator.mark(0x0, 0x130000);
+        let zone = Zone::containing(&frame) as usize;
+        self.free[zone] += 1;
+        if id / bits < self.last[zone] {
+            self.last[zone] = id / bits;
+        }
+    }
 
-        allocator
+    /// Returns the id of the first frame of the zone and the id after its last frame that is
+    /// covered by the bitmaps.
+    fn bounds(&self, zone: Zone) -> (usize, usize) {
+        let total = self.amount * mem::size_of::<usize>() * 8;
+        let (start, end) = zone.frames();
+        (cmp::min(start, total), cmp::min(end, total))
     }
 
-    fn mark(&self, addr: usize, length: usize) {
-        let n = length / Frame::SIZE + 1;
-        for i in 0..n {
-            let p = addr + i * Frame::SIZE;
-            Bitmap::containing(self.base, p).set(Bitmap::offset(p));
+    /// Counts the free frames of every zone.
+    fn count_free(&mut self) {
+        let base = self.base;
+        for &zone in Zone::PREFERENCE.iter() {
+            let (start, end) = self.bounds(zone);
+            let mut free = 0;
+            Bitmap::words(start, end, |i, mask| {
+                free += (!Bitmap::from(base, i).get() & mask).count_ones() as usize;
+                None::<()>
+            });
+            self.free[zone as usize] = free;
         }
     }
 
+    /// Marks the frames from `start` up to the exclusive `end` address as used.
+    fn mark(&self, start: usize, end: usize) {
+        let range = Frame::range(Frame::containing(start), Frame::containing(end - 1));
+        for frame in range {
+            self.mark_frame(&frame);
+        }
+    }
+
+    fn mark_frame(&self, frame: &Frame) {
+        Bitmap::containing(self.base, frame.base()).set(Bitmap::offset(frame.base()));
+    }
+
+    fn unmark_frame(&self, frame: &Frame) {
+        Bitmap::containing(self.base, frame.base()).unset(Bitmap::offset(frame.base()));
+    }
+
+    /// Returns the address and the amount of the frames the bitmaps and reference counts occupy.
     pub fn used(&self) -> (usize, usize) {
-        (
-            self.base,
-            (self.amount * mem::size_of::<usize>()) / Frame::SIZE + 1,
-        )
+        let bits = mem::size_of::<usize>() * 8;
+        let size = self.amount * (mem::size_of::<usize>() + bits * mem::size_of::<u16>());
+        (self.base, (size + Frame::SIZE - 1) / Frame::SIZE)
     }
 }
 
@@ -83,9 +267,9 @@ impl Bitmap {
         frame.id() % (mem::size_of::<usize>() * 8)
     }
 
-    fn zero(&mut self) {
+    fn fill(&mut self) {
         unsafe {
-            *self.ptr = 0x0;
+            *self.ptr = !0x0;
         }
     }
 
@@ -101,6 +285,53 @@ impl Bitmap {
         }
     }
 
+    /// Calls `f` with the index of each bitmap and the mask of its bits covering the frames from
+    /// `start` up to the exclusive `end` id. Stops early and returns the result once `f` returns
+    /// `Some`.
+    fn words<F, R>(start: usize, end: usize, mut f: F) -> Option<R>
+    where
+        F: FnMut(usize, usize) -> Option<R>,
+    {
+        let bits = mem::size_of::<usize>() * 8;
+        if start >= end {
+            return None;
+        }
+        for i in start / bits..(end + bits - 1) / bits {
+            let first = cmp::max(start, i * bits) - i * bits;
+            let last = cmp::min(end, (i + 1) * bits) - i * bits;
+            if let Some(result) = f(i, Bitmap::mask(first, last)) {
+                return Some(result);
+            }
+        }
+        None
+    }
+
+    fn get(&self) -> usize {
+        unsafe { *self.ptr }
+    }
+
+    fn set_mask(&mut self, mask: usize) {
+        unsafe {
+            *self.ptr = *self.ptr | mask;
+        }
+    }
+
+    fn unset_mask(&mut self, mask: usize) {
+        unsafe {
+            *self.ptr = *self.ptr & !mask;
+        }
+    }
+
+    /// Returns the mask of the bits from `start` up to the exclusive `end` offset.
+    fn mask(start: usize, end: usize) -> usize {
+        let bits = mem::size_of::<usize>() * 8;
+        if end - start == bits {
+            !0x0
+        } else {
+            ((1 << (end - start)) - 1) << start
+        }
+    }
+
     fn next(&mut self) -> Option<usize> {
         for i in 0..(mem::size_of::<usize>() * 8) {
             unsafe {
@@ -116,13 +347,8 @@ impl Bitmap {
 
 impl Allocator for BitmapAllocator {
     fn allocate(&mut self) -> Option<Frame> {
-        for i in self.last..self.amount {
-            if i > self.last {
-                self.last = i;
-            }
-            let offset = Bitmap::from(self.base, i).next();
-            if offset.is_some() {
-                let frame = Frame { id: i * mem::size_of::<usize>() * 8 + offset.unwrap() };
+        for &zone in Zone::PREFERENCE.iter() {
+            if let Some(frame) = self.allocate_in(zone) {
                 return Some(frame);
             }
         }
@@ -130,7 +356,66 @@ impl Allocator for BitmapAllocator {
     }
 
     fn deallocate(&mut self, frame: Frame) {
-        let mut bitmap = Bitmap::containing(self.base, frame.base());
-        bitmap.unset(Bitmap::offset(frame.base()));
+        self.deallocate_range(frame, 1);
+    }
+
+    fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<Frame> {
+        for &zone in Zone::PREFERENCE.iter() {
+            if let Some(frame) = self.allocate_contiguous_in(zone, count, align) {
+                return Some(frame);
+            }
+        }
+        None
+    }
+
+    fn deallocate_range(&mut self, start: Frame, count: usize) {
+        let bits = mem::size_of::<usize>() * 8;
+        let (first, end) = (start.id(), start.id() + count);
+        Bitmap::words(first, end, |i, mask| {
+            let ids = cmp::max(first, i * bits)..cmp::min(end, (i + 1) * bits);
+            // Frames with a single reference are freed a whole bitmap at a time.
+            if ids.clone().all(|id| unsafe { *self.references_mut(id) } == 1) {
+                for id in ids.clone() {
+                    unsafe {
+                        *self.references_mut(id) = 0;
+                    }
+                }
+                Bitmap::from(self.base, i).unset_mask(mask);
+
+                let zone = Zone::containing(&Frame { id: i * bits }) as usize;
+                self.free[zone] += ids.len();
+                if i < self.last[zone] {
+                    self.last[zone] = i;
+                }
+                return None::<()>;
+            }
+
+            for id in ids {
+                let references = unsafe { &mut *self.references_mut(id) };
+                match *references {
+                    // Reserved frames are never freed.
+                    0 => {}
+                    1 => {
+                        *references = 0;
+                        self.release(id);
+                    }
+                    _ => *references -= 1,
+                }
+            }
+            None
+        });
+    }
+
+    fn retain(&mut self, frame: &Frame) {
+        let references = unsafe { &mut *self.references_mut(frame.id()) };
+        if *references > 0 {
+            *references = references
+                .checked_add(1)
+                .expect("Too many references to frame.");
+        }
+    }
+
+    fn references(&self, frame: &Frame) -> usize {
+        unsafe { *self.references_mut(frame.id()) as usize }
     }
 }
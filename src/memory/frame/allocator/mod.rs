@@ -6,4 +6,37 @@ pub mod bitmap;
 pub trait Allocator {
     fn allocate(&mut self) -> Option<Frame>;
     fn deallocate(&mut self, frame: Frame);
+
+    /// Allocates `count` physically contiguous frames and returns the first one. The id of the
+    /// first frame is a multiple of `align`, which has to be a power of two.
+    ///
+    /// Allocators that cannot guarantee contiguity only serve single, unaligned frames.
+    fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<Frame> {
+        assert!(align.is_power_of_two(), "Alignment must be a power of two.");
+        if count == 1 && align == 1 {
+            self.allocate()
+        } else {
+            None
+        }
+    }
+
+    /// Adds a reference to an allocated frame, which is then only freed once every reference has
+    /// been dropped by `deallocate`.
+    ///
+    /// Allocators without reference counts ignore this and free a frame on its first
+    /// deallocation.
+    fn retain(&mut self, _frame: &Frame) {}
+
+    /// Returns the amount of references to the frame, or zero if the allocator does not track
+    /// it.
+    fn references(&self, _frame: &Frame) -> usize {
+        0
+    }
+
+    /// Deallocates `count` contiguous frames, starting with `start`.
+    fn deallocate_range(&mut self, start: Frame, count: usize) {
+        for id in start.id()..start.id() + count {
+            self.deallocate(Frame { id: id });
+        }
+    }
 }
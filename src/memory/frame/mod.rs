@@ -1,10 +1,12 @@
 pub use self::allocator::Allocator;
 pub use self::allocator::area::AreaAllocator;
 pub use self::allocator::bitmap::BitmapAllocator;
+pub use self::zone::Zone;
 
 use core::iter::Iterator;
 
 mod allocator;
+mod zone;
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Frame {
@@ -0,0 +1,41 @@
+use memory::frame::Frame;
+
+/// A range of physical memory that some devices are restricted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Zone {
+    /// The memory below 16 MiB, reachable by legacy ISA DMA.
+    Dma,
+    /// The memory below 4 GiB, reachable by 32-bit devices.
+    Dma32,
+    /// All the memory above 4 GiB.
+    Normal,
+}
+
+impl Zone {
+    /// The number of zones.
+    pub const COUNT: usize = 3;
+
+    /// The zones in the order they are used for allocations that do not ask for a zone, so the
+    /// restricted zones are left for the devices that need them.
+    pub const PREFERENCE: [Zone; Zone::COUNT] = [Zone::Normal, Zone::Dma32, Zone::Dma];
+
+    /// Returns the zone the frame belongs to.
+    pub fn containing(frame: &Frame) -> Zone {
+        if frame.base() < 0x100_0000 {
+            Zone::Dma
+        } else if frame.base() < 0x1_0000_0000 {
+            Zone::Dma32
+        } else {
+            Zone::Normal
+        }
+    }
+
+    /// Returns the id of the first frame in the zone and the id after the last one.
+    pub fn frames(&self) -> (usize, usize) {
+        match *self {
+            Zone::Dma => (0, 0x100_0000 / Frame::SIZE),
+            Zone::Dma32 => (0x100_0000 / Frame::SIZE, 0x1_0000_0000 / Frame::SIZE),
+            Zone::Normal => (0x1_0000_0000 / Frame::SIZE, !0x0 / Frame::SIZE),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use buddy;
+use memory::{self, MemoryController};
+use memory::paging::{self, Page};
+use util::log::{Level, Logger};
+
+/// Returns the start address and size of the virtual memory the heap may occupy.
+pub fn reserved() -> (usize, usize) {
+    (buddy::BASE, buddy::SIZE)
+}
+
+/// Returns the start address and size of the memory that has to be mapped before the first
+/// allocation. The heap maps its regions itself as it grows, so there is none.
+pub fn region() -> (usize, usize) {
+    (buddy::BASE, 0)
+}
+
+/// Hooks the kernel into the heap allocator.
+pub fn init() {
+    buddy::set_region_handlers(grow, shrink);
+    buddy::set_oom_handler(oom);
+}
+
+/// Maps the pages of a region the heap is about to grow into. Fails if the memory controller is
+/// in use, since the heap may be used while it is held.
+fn grow(start: usize, size: usize) -> bool {
+    let grown = memory::try_with(|mcon| {
+        let &mut MemoryController {
+            ref mut space,
+            ref mut allocator,
+            ..
+        } = mcon;
+        let table = space.table_mut();
+
+        let pages = Page::range(Page::containing(start), Page::containing(start + size - 1));
+        for page in pages.clone() {
+            if let Err(error) = table.try_map(page, paging::WRITABLE, allocator) {
+                log!(Level::Warn, "Could not grow the heap: {}", error);
+                let first = Page::containing(start);
+                let mapped = Page::range(first, Page::containing(page.base() - 1));
+                table
+                    .unmap_range(mapped, allocator)
+                    .expect("Heap pages mapped while growing could not be unmapped");
+                return false;
+            }
+        }
+        true
+    });
+    grown.unwrap_or(false)
+}
+
+/// Unmaps the pages of a region the heap no longer uses and frees their frames. Returns whether
+/// the region has been released, which is deferred while the memory controller is in use, like
+/// when a value is dropped inside [`memory::with`](../fn.with.html).
+fn shrink(start: usize, size: usize) -> bool {
+    let shrunk = memory::try_with(|mcon| {
+        let &mut MemoryController {
+            ref mut space,
+            ref mut allocator,
+            ..
+        } = mcon;
+        let table = space.table_mut();
+
+        let pages = Page::range(Page::containing(start), Page::containing(start + size - 1));
+        match table.unmap_range(pages, allocator) {
+            Ok(()) => true,
+            Err(error) => {
+                log!(Level::Warn, "Could not shrink the heap: {}", error);
+                false
+            }
+        }
+    });
+    shrunk.unwrap_or(false)
+}
+
+/// Called by the heap allocator when it cannot satisfy a request.
+fn oom(size: usize, align: usize) -> bool {
+    log!(
+        Level::Warn,
+        "Heap exhausted while allocating {} bytes aligned to {}",
+        size,
+        align
+    );
+    dump(Level::Warn);
+
+    // The heap already gave back what the slab caches held on to, and there is nothing else the
+    // kernel could release, so the allocation fails.
+    false
+}
+
+/// Logs the usage of the heap and the statistics of every slab cache.
+pub fn dump(level: Level) {
+    log!(level, "Heap: {}", buddy::heap_stats());
+    for stats in buddy::slab_stats().iter() {
+        log!(level, "{}", stats);
+    }
+}
+
+/// Logs every heap allocation that has not been freed yet, along with its call site.
+#[cfg(feature = "heap-trace")]
+pub fn dump_outstanding() {
+    let untracked = buddy::trace::outstanding(|allocation| {
+        log!(
+            Level::Warn,
+            "{} bytes at {:#x} allocated from {:#x}",
+            allocation.size,
+            allocation.ptr,
+            allocation.site
+        );
+    });
+    if untracked > 0 {
+        log!(Level::Warn, "{} allocations were not tracked", untracked);
+    }
+}
+
+#[cfg(not(feature = "heap-trace"))]
+pub fn dump_outstanding() {}
@@ -0,0 +1,30 @@
+use bump_allocator;
+use util::log::{Level, Logger};
+
+/// Returns the start address and size of the virtual memory the heap may occupy.
+pub fn reserved() -> (usize, usize) {
+    (bump_allocator::START, bump_allocator::SIZE)
+}
+
+/// Returns the start address and size of the memory that has to be mapped before the first
+/// allocation. The heap cannot grow, so that is all of it.
+pub fn region() -> (usize, usize) {
+    reserved()
+}
+
+pub fn init() {}
+
+/// Logs the usage of the heap.
+pub fn dump(level: Level) {
+    let stats = bump_allocator::heap_stats();
+    log!(
+        level,
+        "Heap: {}/{} bytes used by {} allocations",
+        stats.used,
+        stats.size,
+        stats.allocations
+    );
+}
+
+/// Allocations are never freed, so there is nothing to track.
+pub fn dump_outstanding() {}
@@ -0,0 +1,20 @@
+//! The kernel heap. The allocator behind it is chosen with one of the `heap-buddy`, `heap-slab`
+//! or `heap-bump` cargo features.
+
+#[cfg(all(feature = "heap-buddy", feature = "heap-bump"))]
+compile_error!(
+    "The heap-bump feature cannot be combined with heap-buddy, which is part of the default \
+     features. Build with --no-default-features to use heap-bump."
+);
+#[cfg(not(any(feature = "heap-buddy", feature = "heap-bump")))]
+compile_error!("One of the heap-buddy, heap-slab or heap-bump features has to be enabled");
+
+#[cfg(feature = "heap-buddy")]
+pub use self::buddy::{dump, dump_outstanding, init, region, reserved};
+#[cfg(feature = "heap-bump")]
+pub use self::bump::{dump, dump_outstanding, init, region, reserved};
+
+#[cfg(feature = "heap-buddy")]
+mod buddy;
+#[cfg(feature = "heap-bump")]
+mod bump;
@@ -1,34 +1,427 @@
 pub use memory::stack::Stack;
-pub use memory::paging::remap_kernel;
+pub use memory::paging::{remap_kernel, Flags, InactiveTable, MapError, NO_EXEC, PRESENT, WRITABLE};
+pub use memory::vma::{AddressSpace, Area, AreaError, Kind};
+pub use memory::frame::Zone;
 
+use core::cmp;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 use multiboot2::BootInformation;
-use self::frame::{Frame, BitmapAllocator, AreaAllocator};
-use self::paging::ActiveTable;
+use self::frame::{Allocator, Frame, BitmapAllocator, AreaAllocator};
+use self::paging::{ActiveTable, Page, PageSize, TempPage};
+use self::vma::{AddressSpace, Areas};
+use sync::Mutex;
 use util::log::{Logger, Level};
 
 mod frame;
+pub mod heap;
 mod paging;
 mod stack;
+mod vma;
+
+/// The virtual memory the kernel hands out areas from. It starts above the identity mapped low
+/// memory and ends with the first P4 entry, whose tables every address space shares. Identity
+/// mapped memory above its start, like the records of the frame allocator on machines with a lot
+/// of memory, is reserved as areas.
+const KERNEL_SPACE: (usize, usize) = (0x400_0000, 0x0000_0080_0000_0000);
+
+/// The virtual memory private to each address space, the rest of the lower half of the address
+/// space. A fork gets a copy-on-write copy of it.
+const PRIVATE_SPACE: (usize, usize) = (0x0000_0080_0000_0000, 0x0000_8000_0000_0000);
+
+/// The value of [`PROTECTED`](static.PROTECTED.html).
+const PROTECTED_VALUE: u64 = 0x5afe_5afe_5afe_5afe;
+
+/// A value in `.rodata`, which [`test_write_protect`](fn.test_write_protect.html) writes to.
+static PROTECTED: u64 = PROTECTED_VALUE;
+
+/// The base of the page a write is expected to fault in, or 0 if no fault is expected.
+static EXPECTED_FAULT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// The memory controller of the kernel, available once [`init`](fn.init.html) has run.
+static CONTROLLER: Mutex<Option<MemoryController>> = Mutex::new(None);
 
 pub struct MemoryController {
-    table: ActiveTable,
+    space: AddressSpace<ActiveTable>,
     allocator: BitmapAllocator,
-    stack_allocator: stack::StackAllocator,
+    /// The page other tables and frames are mapped to while they are edited.
+    temp: TempPage,
 }
 
 impl MemoryController {
-    pub fn allocate_stack(&mut self, pages: usize) -> Option<stack::Stack> {
+    /// Allocates a stack with the given name and amount of pages, and an unmapped guard page below
+    /// it. An overflow into the guard page is reported with the name of the stack.
+    pub fn allocate_stack(&mut self, name: &'static str, pages: usize) -> Option<Stack> {
+        self.stack(name, pages, Kind::Mapped)
+    }
+
+    /// Allocates a stack like [`allocate_stack`](#method.allocate_stack), whose pages are only
+    /// mapped once they are touched. The fault that maps a page cannot be handled while the
+    /// controller is held, so code running inside [`with`](fn.with.html) must not use the stack.
+    pub fn allocate_lazy_stack(&mut self, name: &'static str, pages: usize) -> Option<Stack> {
+        self.stack(name, pages, Kind::Lazy)
+    }
+
+    /// Frees a stack allocated by this controller. Its pages are unmapped, their frames are freed
+    /// and the range of the stack and its guard page is returned.
+    ///
+    /// # Panics
+    /// The function panics if the stack has not been allocated by this controller.
+    pub fn free_stack(&mut self, stack: Stack) -> (usize, usize) {
+        let &mut MemoryController {
+            ref mut space,
+            ref mut allocator,
+            ..
+        } = self;
+
+        let guard = stack.bottom() - Page::SIZE;
+        let is_guard = space
+            .areas()
+            .find(guard)
+            .map_or(false, |area| area.start == guard && area.kind == Kind::Guard);
+        assert!(
+            is_guard,
+            "Stack at {:#x} has not been allocated by the controller.",
+            stack.bottom()
+        );
+
+        space.remove(stack.bottom(), allocator);
+        space.remove(guard, allocator);
+        (guard, stack.top())
+    }
+
+    /// Allocates a frame from the zone and returns its physical address. Frames in the `Dma` and
+    /// `Dma32` zones are meant for devices that cannot reach all of physical memory.
+    pub fn allocate_frame_in(&mut self, zone: Zone) -> Option<usize> {
+        self.allocator.allocate_in(zone).map(|frame| frame.base())
+    }
+
+    /// Allocates `count` physically contiguous frames and returns the physical address of the
+    /// first one, which is aligned to `align` frames. Frames are taken from the zones in the same
+    /// order as for any other allocation.
+    ///
+    /// # Panics
+    /// The method panics if `count` is zero or `align` is not a power of two.
+    pub fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<usize> {
+        self.allocator
+            .allocate_contiguous(count, align)
+            .map(|frame| frame.base())
+    }
+
+    /// Allocates `count` physically contiguous frames from the zone, like
+    /// [`allocate_contiguous`](#method.allocate_contiguous).
+    pub fn allocate_contiguous_in(
+        &mut self,
+        zone: Zone,
+        count: usize,
+        align: usize,
+    ) -> Option<usize> {
+        self.allocator
+            .allocate_contiguous_in(zone, count, align)
+            .map(|frame| frame.base())
+    }
+
+    /// Frees `count` frames starting at the physical address, which have been allocated by one of
+    /// the methods above.
+    ///
+    /// # Panics
+    /// The method panics if the address is not aligned to the frame size.
+    pub fn free_frames(&mut self, addr: usize, count: usize) {
+        assert!(
+            addr % Frame::SIZE == 0,
+            "Address {:#x} is not aligned to the frame size.",
+            addr
+        );
+        self.allocator.deallocate_range(Frame::containing(addr), count);
+    }
+
+    fn stack(&mut self, name: &'static str, pages: usize, kind: Kind) -> Option<Stack> {
+        if pages == 0 {
+            return None;
+        }
+
+        let &mut MemoryController {
+            ref mut space,
+            ref mut allocator,
+            ..
+        } = self;
+
+        let size = pages * Page::SIZE;
+        let guard = match space.areas().find_free(size + Page::SIZE) {
+            Some(guard) => guard,
+            None => return None,
+        };
+        let bottom = guard + Page::SIZE;
+        // Stacks of the kernel must stay shared with every address space.
+        if bottom + size > KERNEL_SPACE.1 {
+            return None;
+        }
+
+        let guard_area = Area::new(name, guard, Page::SIZE, Flags::empty(), Kind::Guard);
+        if space.insert(guard_area, allocator).is_err() {
+            return None;
+        }
+        let stack_area = Area::new(name, bottom, size, WRITABLE | NO_EXEC, kind);
+        if space.insert(stack_area, allocator).is_err() {
+            space.remove(guard, allocator);
+            return None;
+        }
+
+        Some(Stack::new(bottom + size, bottom))
+    }
+
+    /// Creates an address space that shares the kernel with the active one and gets a
+    /// copy-on-write copy of its private memory, see
+    /// [`AddressSpace::fork`](vma/struct.AddressSpace.html#method.fork).
+    ///
+    /// Pages are copied on a fault, which cannot be handled while the controller is held. Code
+    /// running inside [`with`](fn.with.html) must not write to private memory after a fork.
+    pub fn fork(&mut self) -> Result<AddressSpace<InactiveTable>, MapError> {
+        let &mut MemoryController {
+            ref mut space,
+            ref mut allocator,
+            ref mut temp,
+        } = self;
+
+        space.fork(PRIVATE_SPACE, temp, allocator)
+    }
+
+    /// Switches to the given address space, which is left with the one that was active, see
+    /// [`AddressSpace::switch`](vma/struct.AddressSpace.html#method.switch).
+    pub fn switch(&mut self, space: &mut AddressSpace<InactiveTable>) -> Result<(), AreaError> {
+        self.space.switch(space)
+    }
+
+    /// Frees an inactive address space created by [`fork`](#method.fork), see
+    /// [`AddressSpace::free`](vma/struct.AddressSpace.html#method.free).
+    pub fn free_space(&mut self, space: AddressSpace<InactiveTable>) {
+        let &mut MemoryController {
+            space: ref mut active,
+            ref mut allocator,
+            ref mut temp,
+        } = self;
+
+        space.free(active.table_mut(), temp, allocator);
+    }
+
+    /// Makes the page containing the address writable again after a write to it faulted,
+    /// either because it is copy-on-write or because the write was expected by
+    /// [`test_write_protect`](fn.test_write_protect.html). Returns whether the write can be
+    /// retried.
+    fn allow_write(&mut self, addr: usize, expected: bool) -> bool {
+        let &mut MemoryController {
+            ref mut space,
+            ref mut allocator,
+            ref mut temp,
+        } = self;
+
+        if !expected {
+            return space.copy_on_write(addr, temp, allocator);
+        }
+
+        let page = Page::containing(addr);
+        let table = space.table_mut();
+        match table.flags(page) {
+            Some(flags) => table.protect(Page::range(page, page), flags | WRITABLE).is_ok(),
+            None => false,
+        }
+    }
+
+    /// Maps the page containing the address if it belongs to a lazily mapped area. Returns
+    /// whether the page has been mapped.
+    fn map_on_demand(&mut self, addr: usize) -> bool {
         let &mut MemoryController {
-            ref mut table,
+            ref mut space,
             ref mut allocator,
-            ref mut stack_allocator,
+            ..
         } = self;
 
-        stack_allocator.allocate(table, allocator, pages)
+        space.fault(addr, allocator)
+    }
+}
+
+/// Handles a page fault at the given address, caused by a page that is not present. Returns
+/// whether the page has been mapped and the faulting access can be retried.
+///
+/// The fault cannot be handled while the memory controller is in use, for example when the fault
+/// happened inside [`with`](fn.with.html).
+pub fn handle_page_fault(addr: usize) -> bool {
+    match CONTROLLER.try_lock() {
+        Some(mut controller) => controller
+            .as_mut()
+            .map_or(false, |mcon| mcon.map_on_demand(addr)),
+        None => false,
+    }
+}
+
+/// Handles a page fault at the given address, caused by a write to a read-only page. Returns
+/// whether the page is copy-on-write or the write was expected by
+/// [`test_write_protect`](fn.test_write_protect.html), in which case the page has been made
+/// writable and the write can be retried.
+///
+/// Like [`handle_page_fault`](fn.handle_page_fault.html), the fault cannot be handled while the
+/// memory controller is in use.
+pub fn handle_protection_fault(addr: usize) -> bool {
+    let page = Page::containing(addr);
+    let expected = page.base() != 0 &&
+        EXPECTED_FAULT.compare_and_swap(page.base(), 0, Ordering::SeqCst) == page.base();
+
+    match CONTROLLER.try_lock() {
+        Some(mut controller) => controller
+            .as_mut()
+            .map_or(false, |mcon| mcon.allow_write(addr, expected)),
+        None => false,
+    }
+}
+
+/// Checks that the kernel cannot write to its read-only sections, by writing to a value in
+/// `.rodata`. Returns whether the write faulted.
+pub fn test_write_protect() -> bool {
+    let addr = &PROTECTED as *const u64 as usize;
+    let page = Page::containing(addr);
+    let flags = with(|mcon| mcon.space.table().flags(page)).expect("Read-only data is not mapped");
+
+    EXPECTED_FAULT.store(page.base(), Ordering::SeqCst);
+    // The store is done in assembly, since writing to an immutable static is undefined behaviour.
+    unsafe {
+        asm!("mov qword ptr [$0], $1"
+             :
+             : "r"(addr), "r"(PROTECTED_VALUE)
+             : "memory"
+             : "intel", "volatile");
+    }
+    let faulted = EXPECTED_FAULT.swap(0, Ordering::SeqCst) == 0;
+
+    // Restore the protection the fault handler lifted.
+    with(|mcon| {
+        mcon.space
+            .table_mut()
+            .protect(Page::range(page, page), flags)
+            .expect("Read-only data is not mapped")
+    });
+    faulted
+}
+
+/// Checks that the pages of a lazily mapped stack are mapped on their first access and read as
+/// zeros, even if their frames were used before. The stack is freed afterwards.
+pub fn test_demand_paging() -> bool {
+    // Pages of the second stack likely get the frames the first one filled.
+    (0..2).all(|_| {
+        let stack = with(|mcon| mcon.allocate_lazy_stack("demand paging test", 2))
+            .expect("Could not allocate the demand paging test stack");
+
+        // The pages are touched outside of the controller, since they are mapped on a fault.
+        let words = (stack.top() - stack.bottom()) / 8;
+        let start = stack.bottom() as *mut u64;
+        let zeroed = (0..words).all(|i| {
+            unsafe { ptr::read_volatile(start.offset(i as isize)) == 0 }
+        });
+        for i in 0..words {
+            unsafe { ptr::write_volatile(start.offset(i as isize), !0) };
+        }
+
+        with(|mcon| mcon.free_stack(stack));
+        zeroed
+    })
+}
+
+/// Checks that a fork of the address space gets its own copy of a private page on the first
+/// write to it, while the original page keeps its contents. The fork is freed afterwards.
+pub fn test_fork() -> bool {
+    let addr = PRIVATE_SPACE.0;
+    let area = Area::new("fork test", addr, Page::SIZE, WRITABLE | NO_EXEC, Kind::Mapped);
+    with(|mcon| {
+        let &mut MemoryController {
+            ref mut space,
+            ref mut allocator,
+            ..
+        } = mcon;
+        space.insert(area, allocator)
+    }).expect("Could not map the fork test page");
+
+    // The page is written outside of the controller, since the copy is made on a page fault.
+    let value = addr as *mut u64;
+    unsafe { ptr::write_volatile(value, 1) };
+    let mut fork = with(|mcon| mcon.fork()).expect("Could not fork the address space");
+    with(|mcon| mcon.switch(&mut fork)).expect("Could not switch to the fork");
+    unsafe { ptr::write_volatile(value, 2) };
+    let forked = unsafe { ptr::read_volatile(value) };
+    with(|mcon| mcon.switch(&mut fork)).expect("Could not switch back from the fork");
+    let original = unsafe { ptr::read_volatile(value) };
+
+    with(|mcon| {
+        mcon.free_space(fork);
+        let &mut MemoryController {
+            ref mut space,
+            ref mut allocator,
+            ..
+        } = mcon;
+        space.remove(addr, allocator);
+    });
+    forked == 2 && original == 1
+}
+
+/// Returns the name of the stack whose guard page contains the given address, if there is one.
+///
+/// Like [`handle_page_fault`](fn.handle_page_fault.html), the lookup fails while the memory
+/// controller is in use.
+pub fn overflowed_stack(addr: usize) -> Option<&'static str> {
+    match CONTROLLER.try_lock() {
+        Some(controller) => controller.as_ref().and_then(|mcon| {
+            mcon.space
+                .areas()
+                .find(addr)
+                .and_then(|area| if area.kind == Kind::Guard {
+                    Some(area.name)
+                } else {
+                    None
+                })
+        }),
+        None => None,
     }
 }
 
-pub fn init(info: &BootInformation) -> MemoryController {
+/// Runs the given closure with exclusive access to the memory controller.
+///
+/// The heap cannot grow or shrink while the closure runs, since that needs the controller as
+/// well. Allocations fail once the heap is exhausted, and freed regions are released later.
+///
+/// # Panics
+/// The function panics if the memory subsystem has not been initialized yet.
+pub fn with<F, R>(f: F) -> R
+where
+    F: FnOnce(&mut MemoryController) -> R,
+{
+    let mut controller = CONTROLLER.lock();
+    f(controller.as_mut().expect("Memory has not been initialized"))
+}
+
+/// Runs the given closure with exclusive access to the memory controller, unless the controller
+/// is in use or has not been initialized yet.
+pub fn try_with<F, R>(f: F) -> Option<R>
+where
+    F: FnOnce(&mut MemoryController) -> R,
+{
+    match CONTROLLER.try_lock() {
+        Some(mut controller) => controller.as_mut().map(f),
+        None => None,
+    }
+}
+
+/// Logs the areas of the kernel's address space and every present mapping of the active table
+/// with the given level.
+pub fn dump(level: Level) {
+    with(|mcon| {
+        for area in mcon.space.areas().iter() {
+            log!(level, "{}", area);
+        }
+        for mapping in mcon.space.table().mappings() {
+            log!(level, "{}", mapping);
+        }
+    });
+}
+
+pub fn init(info: &BootInformation) {
     let mmtag = info.memory_map_tag().expect("Memory Map Tag required");
     let elftag = info.elf_sections_tag().expect("ELF Sections Tag required");
 
@@ -60,63 +453,105 @@ pub fn init(info: &BootInformation) -> MemoryController {
     );
 
     // Use a simpler AreaAllocator to get the frames the BitmapAllocator needs to store the
-    // bitmaps.
-    let mut pre_allocator = AreaAllocator::new(
+    // bitmaps. The BitmapAllocator takes over its record of allocated frames afterwards.
+    let pre_allocator = AreaAllocator::new(
         (kernel_start as usize, kernel_end as usize),
         (mb_start as usize, mb_end as usize),
         mmtag.memory_areas(),
     );
 
-    // Find the total memory size.
+    // Find the total size of the available memory.
     let mut memory_size = 0;
     for area in mmtag.memory_areas() {
         memory_size += area.length;
     }
     log!(
         Level::Info,
-        "Total memory size found to be: {} KB",
+        "Available memory found to be: {} KB",
         memory_size / 1024
     );
 
-    let mut allocator = BitmapAllocator::new((memory_size as usize), &mut pre_allocator);
+    let mut allocator = BitmapAllocator::new(
+        mmtag.memory_areas(),
+        (kernel_start as usize, kernel_end as usize),
+        (mb_start as usize, mb_end as usize),
+        pre_allocator,
+    );
+    for &zone in Zone::PREFERENCE.iter() {
+        log!(
+            Level::Info,
+            "{:?} zone has {} free frames",
+            zone,
+            allocator.free(zone)
+        );
+    }
     let reserved = allocator.used();
 
+    // Lay out the virtual memory of the kernel. The heap allocator decides where the heap lies.
+    // The heap maps its regions itself as it grows instead of being backed lazily: its pages are
+    // touched while the controller may be held, when a fault cannot be handled, and running out
+    // of frames has to fail an allocation instead of a page fault.
+    let mut areas = Areas::new(KERNEL_SPACE.0, PRIVATE_SPACE.1);
+    let kernel = (kernel_start as usize, kernel_end as usize);
+    reserve_identity(&mut areas, "kernel", kernel, Flags::empty());
+    let multiboot = (mb_start as usize, mb_end as usize);
+    reserve_identity(&mut areas, "multiboot", multiboot, PRESENT);
+    let records = (reserved.0, reserved.0 + reserved.1 * Frame::SIZE);
+    reserve_identity(&mut areas, "frame allocator", records, WRITABLE);
+    let (heap_start, heap_size) = heap::reserved();
+    areas
+        .insert(Area::new("heap", heap_start, heap_size, WRITABLE, Kind::Reserved))
+        .expect("Could not reserve the heap");
+    // The temporary page gets a P1 table to itself. Its tables come from a TinyAllocator that
+    // cannot be refilled, so the table must not be freed when the pages next to it are unmapped.
+    let size = PageSize::Large.bytes();
+    let temp = areas
+        .find_free(2 * size)
+        .map(|start| (start + size - 1) / size * size)
+        .expect("Could not reserve the temporary page");
+    areas
+        .insert(Area::new("temporary page", temp, size, WRITABLE, Kind::Reserved))
+        .expect("Could not reserve the temporary page");
+
     // Remap the kernel.
-    let mut table = paging::remap_kernel(&mut allocator, info);
+    let mut table = paging::remap_kernel(&mut allocator, info, reserved, Page::containing(temp));
 
-    // Identity map the frames needed by the allocator.
-    for frame in Frame::range(
-        Frame::containing(reserved.0),
-        Frame::containing(reserved.0 + reserved.1),
-    ) {
-        table.map_id(frame, paging::WRITABLE, &mut allocator);
+    // Map the part of the heap the allocator needs before it can serve any request.
+    let (heap_start, heap_size) = heap::region();
+    if heap_size > 0 {
+        let start = Page::containing(heap_start);
+        let end = Page::containing(heap_start + heap_size - 1);
+        table.map_range(Page::range(start, end), paging::WRITABLE, &mut allocator);
     }
 
-    use self::paging::Page;
-    use buddy::{BASE, SIZE};
-
-    let heap_start_page = Page::containing(BASE);
-    let heap_end_page = Page::containing(BASE + SIZE - 1);
-    for page in Page::range(heap_start_page, heap_end_page) {
-        table.map(page, paging::WRITABLE, &mut allocator);
-    }
+    let (heap_start, heap_size) = heap::reserved();
     log!(
         Level::Info,
-        "Heap spans memory region from {:#x} to {:#x}",
-        heap_start_page.base(),
-        heap_end_page.base() + Page::SIZE - 1
+        "Heap may grow from {:#x} to {:#x}",
+        heap_start,
+        heap_start + heap_size - 1
     );
 
-    let stack_allocator = {
-        let alloc_start = heap_end_page + 1;
-        let alloc_end = alloc_start + 100;
-        let alloc_range = Page::range(alloc_start, alloc_end);
-        stack::StackAllocator::new(alloc_range)
-    };
+    // The temporary page of the old table cannot be used with the new one.
+    let temp = TempPage::new(Page::containing(temp), &mut allocator);
 
-    MemoryController {
-        table: table,
+    *CONTROLLER.lock() = Some(MemoryController {
+        space: AddressSpace::new(table, areas),
         allocator: allocator,
-        stack_allocator: stack_allocator,
+        temp: temp,
+    });
+
+    heap::init();
+}
+
+/// Reserves the part of an identity mapped range of memory from `range.0` up to the exclusive
+/// `range.1` that lies in the kernel's virtual memory, so no area is placed on top of it.
+fn reserve_identity(areas: &mut Areas, name: &'static str, range: (usize, usize), flags: Flags) {
+    let start = cmp::max(range.0, KERNEL_SPACE.0) / Page::SIZE * Page::SIZE;
+    let end = (range.1 + Page::SIZE - 1) / Page::SIZE * Page::SIZE;
+    if start < end {
+        areas
+            .insert(Area::new(name, start, end - start, flags, Kind::Reserved))
+            .expect("Could not reserve identity mapped memory");
     }
 }
@@ -0,0 +1,32 @@
+use core::fmt;
+use error::Error;
+
+/// The errors that can occur when changing the mappings of a table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The page is mapped already.
+    AlreadyMapped,
+    /// The page is not mapped.
+    NotMapped,
+    /// A frame for the page or for a page table could not be allocated.
+    FrameAllocationFailed,
+    /// The page lies inside a huge page, or is mapped with a different size.
+    HugePageConflict,
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl Error for MapError {
+    fn description(&self) -> &str {
+        match *self {
+            MapError::AlreadyMapped => "Page is already mapped",
+            MapError::NotMapped => "Page is not mapped",
+            MapError::FrameAllocationFailed => "Could not allocate a frame",
+            MapError::HugePageConflict => "Page conflicts with a huge page",
+        }
+    }
+}
@@ -1,7 +1,9 @@
 use core::ptr::Unique;
-use super::Page;
-use super::table::{Flags, Table, Level4, PRESENT, P4};
+use super::{MapError, Page, PageIter, PageSize};
+use super::walk::MappingIter;
+use super::table::{Entry, Flags, IterableLevel, Table, Level4, HUGE, PRESENT, P4};
 use memory::frame::{self, Frame};
+use memory::paging;
 
 /// Provides methods that allow mapping a physical frame of the
 /// [`Frame`](../../frame/struct.Frame.html) type to a virtual address of the
@@ -28,6 +30,19 @@ impl Mapper {
         Mapper { table: Unique::new(P4) }
     }
 
+    /// Constructs a `Mapper` for the P4 table in the given frame, which is accessed through the
+    /// mapping of physical memory. The table does not need to be active.
+    ///
+    /// # Safety
+    /// See [`Mapper::new()`](#method.new). The frame has to hold a P4 table.
+    ///
+    /// # Panics
+    /// The method panics if physical memory is not mapped, see
+    /// [`paging::direct`](../fn.direct.html).
+    pub unsafe fn at(frame: &Frame) -> Mapper {
+        Mapper { table: Unique::new(paging::phys_to_virt(frame.base()) as *mut _) }
+    }
+
     /// Returns a reference to the P4 table the mapper operates on.
     ///
     /// # Examples
@@ -52,27 +67,87 @@ impl Mapper {
         unsafe { self.table.as_mut() }
     }
 
-    /// Translates a physical address to its corresponding virtual address.
+    /// Translates a virtual address to its corresponding physical address in the table.
     ///
     /// # Examples
     ///
     /// ```
-    /// let v = Mapper::translate(0xFFFF).unwrap();
+    /// let v = m.translate(0xFFFF).unwrap();
     /// ```
-    pub fn translate(addr: usize) -> Option<usize> {
-        let offset = addr % Page::SIZE;
+    pub fn translate(&self, addr: usize) -> Option<usize> {
+        self.lookup(addr).map(|(frame, size)| frame.base() + addr % size.bytes())
+    }
 
-        let page = Page::containing(addr);
-        let frame = {
-            let p3 = unsafe { &*P4 }.next(page.p4_index());
+    /// Returns an iterator over the present mappings of the table, see
+    /// [`MappingIter`](../walk/struct.MappingIter.html).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// for mapping in m.mappings() {
+    ///     log!(Level::Info, "{}", mapping);
+    /// }
+    /// ```
+    pub fn mappings(&self) -> MappingIter {
+        MappingIter::new(self)
+    }
 
-            // TODO: Handle huge pages.
+    /// Returns the size of the page the virtual address is mapped with.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// assert_eq!(m.page_size(0xFFFF), Some(PageSize::Small));
+    /// ```
+    pub fn page_size(&self, addr: usize) -> Option<PageSize> {
+        self.lookup(addr).map(|(_, size)| size)
+    }
 
-            p3.and_then(|p3| p3.next(page.p3_index()))
-                .and_then(|p2| p2.next(page.p2_index()))
-                .and_then(|p1| p1[page.p1_index()].frame())
+    /// Returns the flags the page is mapped with, if it is mapped and not part of a huge page.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let writable = m.flags(page).map_or(false, |flags| flags.contains(WRITABLE));
+    /// ```
+    pub fn flags(&self, page: Page) -> Option<Flags> {
+        self.table()
+            .next(page.p4_index())
+            .and_then(|p3| p3.next(page.p3_index()))
+            .and_then(|p2| p2.next(page.p2_index()))
+            .map(|p1| p1[page.p1_index()].flags())
+            .and_then(|flags| if flags.contains(PRESENT) {
+                Some(flags)
+            } else {
+                None
+            })
+    }
+
+    /// Returns the first frame of the page the virtual address is mapped with, and the size of
+    /// the page.
+    fn lookup(&self, addr: usize) -> Option<(Frame, PageSize)> {
+        let page = Page::containing(addr);
+        let p3 = match self.table().next(page.p4_index()) {
+            Some(p3) => p3,
+            None => return None,
         };
-        frame.map(|f| f.id() * Page::SIZE + offset)
+
+        let entry = &p3[page.p3_index()];
+        if entry.flags().contains(HUGE) {
+            return entry.frame().map(|frame| (frame, PageSize::Huge));
+        }
+        let p2 = match p3.next(page.p3_index()) {
+            Some(p2) => p2,
+            None => return None,
+        };
+
+        let entry = &p2[page.p2_index()];
+        if entry.flags().contains(HUGE) {
+            return entry.frame().map(|frame| (frame, PageSize::Large));
+        }
+        p2.next(page.p2_index())
+            .and_then(|p1| p1[page.p1_index()].frame())
+            .map(|frame| (frame, PageSize::Small))
     }
 
     /// Maps a [`Page`](../struct.Page.html) to a [`Frame`](../../frame/struct.Frame.html).
@@ -92,8 +167,33 @@ impl Mapper {
     where
         A: frame::Allocator,
     {
-        let frame = allocator.allocate().expect("Out of memory");
-        self.map_to(page, frame, flags, allocator)
+        self.try_map(page, flags, allocator).expect("Could not map page")
+    }
+
+    /// Maps a [`Page`](../struct.Page.html) to a newly allocated frame, like
+    /// [`map`](#method.map).
+    ///
+    /// # Errors
+    /// Fails with `FrameAllocationFailed` if no frame is available, and with the errors of
+    /// [`try_map_to`](#method.try_map_to). The frame is freed again if the page cannot be mapped.
+    pub fn try_map<A>(
+        &mut self,
+        page: Page,
+        flags: Flags,
+        allocator: &mut A,
+    ) -> Result<(), MapError>
+    where
+        A: frame::Allocator,
+    {
+        let frame = match allocator.allocate() {
+            Some(frame) => frame,
+            None => return Err(MapError::FrameAllocationFailed),
+        };
+        let result = self.try_map_to(page, frame.clone(), flags, allocator);
+        if result.is_err() {
+            allocator.deallocate(frame);
+        }
+        result
     }
 
     /// Maps a [`Page`](../struct.Page.html) to a specific
@@ -113,12 +213,56 @@ impl Mapper {
     where
         A: frame::Allocator,
     {
-        let mut p3 = self.table_mut().next_or_create(page.p4_index(), allocator);
-        let mut p2 = p3.next_or_create(page.p3_index(), allocator);
-        let mut p1 = p2.next_or_create(page.p2_index(), allocator);
+        self.try_map_to(page, frame, flags, allocator)
+            .expect("Could not map page")
+    }
+
+    /// Maps a [`Page`](../struct.Page.html) to a specific
+    /// [`Frame`](../../frame/struct.Frame.html), like [`map_to`](#method.map_to).
+    ///
+    /// # Errors
+    /// Fails with `AlreadyMapped` if the page is mapped already, with `HugePageConflict` if it
+    /// lies inside a huge page, and with `FrameAllocationFailed` if a page table cannot be
+    /// allocated.
+    pub fn try_map_to<A>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: Flags,
+        allocator: &mut A,
+    ) -> Result<(), MapError>
+    where
+        A: frame::Allocator,
+    {
+        let result = self.set_entry(page, frame, flags, allocator);
+        // Free the tables that were created for the page in vain.
+        if result.is_err() && self.collect(page, PageSize::Small, allocator) {
+            flush_all();
+        }
+        result
+    }
+
+    /// Sets the P1 entry of the page, creating the tables on the path to it as needed.
+    fn set_entry<A>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        flags: Flags,
+        allocator: &mut A,
+    ) -> Result<(), MapError>
+    where
+        A: frame::Allocator,
+    {
+        let mut p3 = self.table_mut()
+            .try_next_or_create(page.p4_index(), allocator)?;
+        let mut p2 = p3.try_next_or_create(page.p3_index(), allocator)?;
+        let mut p1 = p2.try_next_or_create(page.p2_index(), allocator)?;
 
-        assert!(p1[page.p1_index()].is_free());
+        if !p1[page.p1_index()].is_free() {
+            return Err(MapError::AlreadyMapped);
+        }
         p1[page.p1_index()].set(frame, flags | PRESENT);
+        Ok(())
     }
 
     /// Identity maps the given [`Frame`](../../frame/struct.Frame.html) to the
@@ -141,8 +285,126 @@ impl Mapper {
         self.map_to(page, frame, flags, allocator)
     }
 
+    /// Maps a [`Page`](../struct.Page.html) of the given size to the contiguous frames starting
+    /// with `frame`, using a single P2 or P3 entry for large and huge pages. On processors without
+    /// support for huge pages, the range is mapped with large pages instead.
+    ///
+    /// # Panics
+    /// The method may panic if the page or the frame are not aligned to the page size, if anything
+    /// is mapped in the range of the page already, or if a page table cannot be allocated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// m.map_huge(Page::containing(0xFFFF_8000_0000_0000), Frame::containing(0x0),
+    /// PageSize::Large, WRITABLE, allocator);
+    /// ```
+    pub fn map_huge<A>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        size: PageSize,
+        flags: Flags,
+        allocator: &mut A,
+    ) where
+        A: frame::Allocator,
+    {
+        self.try_map_huge(page, frame, size, flags, allocator)
+            .expect("Could not map huge page")
+    }
 
-    /// Unmap the given [`Page`](../struct.Page.html) from the table.
+    /// Maps a [`Page`](../struct.Page.html) of the given size, like
+    /// [`map_huge`](#method.map_huge).
+    ///
+    /// # Errors
+    /// Fails with `AlreadyMapped` if the page is mapped with the same size already, with
+    /// `HugePageConflict` if smaller or larger pages are mapped in its range, and with
+    /// `FrameAllocationFailed` if a page table cannot be allocated. Nothing is mapped in that
+    /// case, and the page tables created on the way are freed again.
+    ///
+    /// # Panics
+    /// The method panics if the page or the frame are not aligned to the page size.
+    pub fn try_map_huge<A>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        size: PageSize,
+        flags: Flags,
+        allocator: &mut A,
+    ) -> Result<(), MapError>
+    where
+        A: frame::Allocator,
+    {
+        assert!(
+            page.base() % size.bytes() == 0 && frame.base() % size.bytes() == 0,
+            "Page and frame must be aligned to the page size."
+        );
+
+        if size == PageSize::Small {
+            return self.try_map_to(page, frame, flags, allocator);
+        }
+        if !size.is_supported() {
+            let large = PageSize::Large.bytes();
+            for offset in (0..size.bytes() / large).map(|i| i * large) {
+                let result = self.try_map_huge(
+                    Page::containing(page.base() + offset),
+                    Frame::containing(frame.base() + offset),
+                    PageSize::Large,
+                    flags,
+                    allocator,
+                );
+                if let Err(error) = result {
+                    for done in (0..offset / large).map(|i| i * large) {
+                        let mapped = Page::containing(page.base() + done);
+                        self.unmap_huge(mapped, PageSize::Large, allocator);
+                    }
+                    return Err(error);
+                }
+            }
+            return Ok(());
+        }
+
+        let result = self.set_huge(page, frame, size, flags, allocator);
+        if result.is_err() && self.collect(page, size, allocator) {
+            flush_all();
+        }
+        result
+    }
+
+    /// Maps an already mapped [`Page`](../struct.Page.html) to another
+    /// [`Frame`](../../frame/struct.Frame.html) and returns the frame it was mapped to before,
+    /// together with the reference the mapping held to it.
+    ///
+    /// # Panics
+    /// See [`unmap`](#method.unmap).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let old = m.replace(page, frame, WRITABLE);
+    /// allocator.deallocate(old);
+    /// ```
+    pub fn replace(&mut self, page: Page, frame: Frame, flags: Flags) -> Frame {
+        let old = {
+            let entry = self.entry_mut(page)
+                .expect("Page is not mapped or part of a huge page");
+            let old = entry.frame().expect("Page is not mapped");
+            entry.set(frame, flags | PRESENT);
+            old
+        };
+
+        use x86_64::instructions::tlb;
+        use x86_64::VirtualAddress;
+        tlb::flush(VirtualAddress(page.base()));
+
+        old
+    }
+
+    /// Unmap the given [`Page`](../struct.Page.html) from the table and return the
+    /// [`Frame`](../../frame/struct.Frame.html) it was mapped to. The reference the mapping held
+    /// to the frame is handed to the caller, see [`unmap_free`](#method.unmap_free).
+    ///
+    /// Page tables that become empty are freed, except for the P4 table.
     ///
     /// # Panics
     /// The method may panic if the [`Page`](../struct.Page.html) has the
@@ -151,27 +413,348 @@ impl Mapper {
     /// # Examples
     ///
     /// ```
-    /// m.unmap(Page::containing(0xFFFF), allocator);
+    /// let frame = m.unmap(Page::containing(0xFFFF), allocator);
     /// ```
-    pub fn unmap<A>(&mut self, page: Page, _: &mut A)
+    pub fn unmap<A>(&mut self, page: Page, allocator: &mut A) -> Frame
     where
         A: frame::Allocator,
     {
-        assert!(Mapper::translate(page.base()).is_some());
+        self.try_unmap(page, allocator).expect("Could not unmap page")
+    }
 
-        let p1 = self.table_mut()
-            .next_mut(page.p4_index())
-            .and_then(|p3| p3.next_mut(page.p3_index()))
-            .and_then(|p2| p2.next_mut(page.p2_index()))
-            .expect("Mapping code does not support huge pages");
+    /// Unmap the given [`Page`](../struct.Page.html) from the table and return the
+    /// [`Frame`](../../frame/struct.Frame.html) it was mapped to, like [`unmap`](#method.unmap).
+    ///
+    /// # Errors
+    /// Fails with `NotMapped` if the page is not mapped, and with `HugePageConflict` if it is
+    /// part of a huge page.
+    pub fn try_unmap<A>(&mut self, page: Page, allocator: &mut A) -> Result<Frame, MapError>
+    where
+        A: frame::Allocator,
+    {
+        match self.page_size(page.base()) {
+            None => return Err(MapError::NotMapped),
+            Some(PageSize::Small) => {}
+            Some(_) => return Err(MapError::HugePageConflict),
+        }
+
+        let frame = self.unmap_temporary(page);
+        if self.collect(page, PageSize::Small, allocator) {
+            flush_all();
+        }
+        Ok(frame)
+    }
+
+    /// Unmap a [`Page`](../struct.Page.html) of the given size that has been mapped with
+    /// [`map_huge`](#method.map_huge), and return its first frame. Like with
+    /// [`unmap`](#method.unmap), the reference to the frames is handed to the caller and empty page
+    /// tables are freed.
+    ///
+    /// # Panics
+    /// The method may panic if the page is not mapped with the given size.
+    pub fn unmap_huge<A>(&mut self, page: Page, size: PageSize, allocator: &mut A) -> Frame
+    where
+        A: frame::Allocator,
+    {
+        assert!(
+            page.base() % size.bytes() == 0,
+            "Page must be aligned to the page size."
+        );
+        assert!(
+            self.page_size(page.base()) == Some(size),
+            "Page is not mapped with the given size."
+        );
 
-        // let frame = p1[page.p1_index()].frame().unwrap();
-        p1[page.p1_index()].free();
+        let frame = match size {
+            PageSize::Small => return self.unmap(page, allocator),
+            PageSize::Large => {
+                let p2 = self.table_mut()
+                    .next_mut(page.p4_index())
+                    .and_then(|p3| p3.next_mut(page.p3_index()))
+                    .unwrap();
+                let frame = p2[page.p2_index()].frame().unwrap();
+                p2[page.p2_index()].free();
+                frame
+            }
+            PageSize::Huge => {
+                let p3 = self.table_mut().next_mut(page.p4_index()).unwrap();
+                let frame = p3[page.p3_index()].frame().unwrap();
+                p3[page.p3_index()].free();
+                frame
+            }
+        };
+
+        self.collect(page, size, allocator);
+        flush_all();
+        frame
+    }
+
+    /// Unmap the given [`Page`](../struct.Page.html) from the table and drop its reference to the
+    /// frame, which is freed once no other mapping refers to it.
+    ///
+    /// # Panics
+    /// See [`unmap`](#method.unmap).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// m.unmap_free(Page::containing(0xFFFF), allocator);
+    /// ```
+    pub fn unmap_free<A>(&mut self, page: Page, allocator: &mut A)
+    where
+        A: frame::Allocator,
+    {
+        let frame = self.unmap(page, allocator);
+        allocator.deallocate(frame);
+    }
+
+    /// Unmap the given [`Page`](../struct.Page.html) like [`unmap`](#method.unmap), but keep the
+    /// page tables even if they become empty. This is meant for the temporary page, which is
+    /// mapped again at the same address right away.
+    ///
+    /// # Panics
+    /// See [`unmap`](#method.unmap).
+    pub fn unmap_temporary(&mut self, page: Page) -> Frame {
+        let frame = self.clear(page);
 
         use x86_64::instructions::tlb;
         use x86_64::VirtualAddress;
         tlb::flush(VirtualAddress(page.base()));
 
-        // allocator.deallocate(frame);
+        frame
+    }
+
+    /// Maps every [`Page`](../struct.Page.html) of the range to a newly allocated frame.
+    ///
+    /// # Panics
+    /// See [`map`](#method.map).
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let range = Page::range(Page::containing(0x4000_0000), Page::containing(0x4000_ffff));
+    /// m.map_range(range, WRITABLE, allocator);
+    /// ```
+    pub fn map_range<A>(&mut self, range: PageIter, flags: Flags, allocator: &mut A)
+    where
+        A: frame::Allocator,
+    {
+        for page in range {
+            self.map(page, flags, allocator);
+        }
+    }
+
+    /// Unmaps every [`Page`](../struct.Page.html) of the range, drops the references to their
+    /// frames and frees the page tables that become empty. The TLB is flushed once at the end.
+    ///
+    /// # Errors
+    /// Fails with `NotMapped` if a page of the range is not mapped, and with `HugePageConflict` if
+    /// it is part of a huge page. No page is unmapped in that case.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let range = Page::range(Page::containing(0x4000_0000), Page::containing(0x4000_ffff));
+    /// m.unmap_range(range, allocator).expect("Range is not mapped");
+    /// ```
+    pub fn unmap_range<A>(&mut self, range: PageIter, allocator: &mut A) -> Result<(), MapError>
+    where
+        A: frame::Allocator,
+    {
+        self.check_range(range.clone(), true)?;
+        for page in range {
+            self.release(page, allocator);
+        }
+        flush_all();
+        Ok(())
+    }
+
+    /// Unmaps the mapped pages of the range like [`unmap_range`](#method.unmap_range), and skips
+    /// the pages that are not mapped, like those of a lazily mapped area.
+    ///
+    /// # Errors
+    /// Fails with `HugePageConflict` if a page of the range is part of a huge page. No page is
+    /// unmapped in that case.
+    pub fn unmap_present<A>(&mut self, range: PageIter, allocator: &mut A) -> Result<(), MapError>
+    where
+        A: frame::Allocator,
+    {
+        self.check_range(range.clone(), false)?;
+        for page in range {
+            if self.page_size(page.base()).is_some() {
+                self.release(page, allocator);
+            }
+        }
+        flush_all();
+        Ok(())
+    }
+
+    /// Changes the flags of every [`Page`](../struct.Page.html) of the range, keeping the frames
+    /// they are mapped to. The TLB is flushed once at the end.
+    ///
+    /// # Errors
+    /// Fails with `NotMapped` if a page of the range is not mapped, and with `HugePageConflict` if
+    /// it is part of a huge page. No page is changed in that case.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let range = Page::range(Page::containing(0x4000_0000), Page::containing(0x4000_ffff));
+    /// m.protect(range, NO_EXEC).expect("Range is not mapped");
+    /// ```
+    pub fn protect(&mut self, range: PageIter, flags: Flags) -> Result<(), MapError> {
+        self.check_range(range.clone(), true)?;
+
+        for page in range {
+            let entry = self.entry_mut(page).unwrap();
+            let frame = entry.frame().unwrap();
+            entry.set(frame, flags | PRESENT);
+        }
+        flush_all();
+        Ok(())
+    }
+
+    /// Sets the P2 or P3 entry of a large or huge page, creating the tables on the way to it.
+    fn set_huge<A>(
+        &mut self,
+        page: Page,
+        frame: Frame,
+        size: PageSize,
+        flags: Flags,
+        allocator: &mut A,
+    ) -> Result<(), MapError>
+    where
+        A: frame::Allocator,
+    {
+        let p3 = self.table_mut()
+            .try_next_or_create(page.p4_index(), allocator)?;
+        let entry = match size {
+            PageSize::Small => unreachable!(),
+            PageSize::Large => {
+                let p2 = p3.try_next_or_create(page.p3_index(), allocator)?;
+                &mut p2[page.p2_index()]
+            }
+            PageSize::Huge => &mut p3[page.p3_index()],
+        };
+        if !entry.is_free() {
+            return Err(if entry.flags().contains(HUGE) {
+                MapError::AlreadyMapped
+            } else {
+                MapError::HugePageConflict
+            });
+        }
+        entry.set(frame, flags | PRESENT | HUGE);
+        Ok(())
+    }
+
+    /// Checks that no page of the range is part of a huge page, and that every page is mapped if
+    /// `mapped` is set.
+    fn check_range(&self, range: PageIter, mapped: bool) -> Result<(), MapError> {
+        for page in range {
+            match self.page_size(page.base()) {
+                Some(PageSize::Small) => {}
+                Some(_) => return Err(MapError::HugePageConflict),
+                None if mapped => return Err(MapError::NotMapped),
+                None => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Unmaps a page that is known to be mapped, drops the reference to its frame and frees the
+    /// page tables that become empty. The TLB is left to the caller to flush.
+    fn release<A>(&mut self, page: Page, allocator: &mut A)
+    where
+        A: frame::Allocator,
+    {
+        let frame = self.clear(page);
+        allocator.deallocate(frame);
+        self.collect(page, PageSize::Small, allocator);
     }
+
+    /// Returns the P1 entry of the page, if the page is not part of a huge page.
+    fn entry_mut(&mut self, page: Page) -> Option<&mut Entry> {
+        self.table_mut()
+            .next_mut(page.p4_index())
+            .and_then(|p3| p3.next_mut(page.p3_index()))
+            .and_then(|p2| p2.next_mut(page.p2_index()))
+            .map(|p1| &mut p1[page.p1_index()])
+    }
+
+    /// Clears the P1 entry of the page without flushing the TLB, and returns the frame it was
+    /// mapped to.
+    fn clear(&mut self, page: Page) -> Frame {
+        let entry = self.entry_mut(page)
+            .expect("Page is not mapped or part of a huge page");
+        let frame = entry.frame().expect("Page is not mapped");
+        entry.free();
+        frame
+    }
+
+    /// Frees the P1, P2 and P3 tables on the path to the page if they are empty, starting with
+    /// the table that held the entry of a page of the given size. Tables that do not exist are
+    /// skipped. Returns whether a table was freed, in which case the TLB needs to be flushed, since
+    /// the freed tables are still reachable through the recursive mapping.
+    fn collect<A>(&mut self, page: Page, size: PageSize, allocator: &mut A) -> bool
+    where
+        A: frame::Allocator,
+    {
+        // The last P4 entry maps the tables themselves.
+        if page.p4_index() == 511 {
+            return false;
+        }
+
+        // A table is only looked at if the one below it is gone, otherwise it cannot be empty.
+        let (p1, gone) = if size != PageSize::Small {
+            (None, true)
+        } else {
+            self.table_mut()
+                .next_mut(page.p4_index())
+                .and_then(|p3| p3.next_mut(page.p3_index()))
+                .map_or((None, true), |p2| release_empty(p2, page.p2_index()))
+        };
+
+        let (p2, gone) = if size == PageSize::Huge || !gone {
+            (None, gone)
+        } else {
+            self.table_mut()
+                .next_mut(page.p4_index())
+                .map_or((None, true), |p3| release_empty(p3, page.p3_index()))
+        };
+
+        let p3 = if gone {
+            release_empty(self.table_mut(), page.p4_index()).0
+        } else {
+            None
+        };
+
+        let collected = p1.is_some() || p2.is_some() || p3.is_some();
+        for frame in p1.into_iter().chain(p2).chain(p3) {
+            allocator.deallocate(frame);
+        }
+        collected
+    }
+}
+
+/// Frees the entry of the table if the table it points to is empty. Returns the frame of the
+/// freed table, and whether the table is gone, because it was freed or did not exist.
+fn release_empty<L>(table: &mut Table<L>, index: usize) -> (Option<Frame>, bool)
+where
+    L: IterableLevel,
+{
+    match table.next(index).map(|next| next.is_empty()) {
+        Some(true) => {
+            let frame = table[index].frame();
+            table[index].free();
+            (frame, true)
+        }
+        Some(false) => (None, false),
+        None => (None, !table[index].flags().contains(PRESENT)),
+    }
+}
+
+/// Flushes the whole TLB.
+fn flush_all() {
+    use x86_64::instructions::tlb;
+    tlb::flush_all();
 }
@@ -1,13 +1,38 @@
+pub use self::error::MapError;
 pub use self::mapper::Mapper;
-pub use self::table::{ActiveTable, PRESENT, WRITABLE};
+pub use self::table::{ActiveTable, Flags, InactiveTable, TempPage, COPY_ON_WRITE, NO_EXEC, PRESENT,
+                      WRITABLE};
+pub use self::walk::{Mapping, MappingIter};
 
 use core::ops::Add;
+use core::sync::atomic::{AtomicBool, Ordering, ATOMIC_BOOL_INIT};
 use memory::frame::{self, Frame};
-use memory::paging::table::{Flags, InactiveTable, TempPage};
 use multiboot2::BootInformation;
 
+/// The virtual address all of physical memory is mapped at with the `phys-offset` feature.
+pub const PHYS_OFFSET: usize = 0xffff_8000_0000_0000;
+
+/// Whether the physical memory is mapped at [`PHYS_OFFSET`](constant.PHYS_OFFSET.html) in the
+/// active table.
+static DIRECT: AtomicBool = ATOMIC_BOOL_INIT;
+
+/// Returns whether page tables and other frames are accessed through the mapping of physical
+/// memory at [`PHYS_OFFSET`](constant.PHYS_OFFSET.html), instead of the recursive mapping.
+pub fn direct() -> bool {
+    cfg!(feature = "phys-offset") && DIRECT.load(Ordering::Relaxed)
+}
+
+/// Returns the virtual address the physical address is mapped at, if [`direct`](fn.direct.html)
+/// access is available.
+pub fn phys_to_virt(addr: usize) -> usize {
+    assert!(direct(), "Physical memory is not mapped.");
+    PHYS_OFFSET + addr
+}
+
+mod error;
 mod mapper;
 mod table;
+mod walk;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Page {
@@ -51,6 +76,48 @@ impl Page {
     }
 }
 
+/// The sizes a page can have.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// A 4 KiB page mapped by a P1 entry.
+    Small,
+    /// A 2 MiB page mapped by a P2 entry.
+    Large,
+    /// A 1 GiB page mapped by a P3 entry.
+    Huge,
+}
+
+impl PageSize {
+    /// Returns the size of the page in bytes.
+    pub fn bytes(&self) -> usize {
+        match *self {
+            PageSize::Small => Page::SIZE,
+            PageSize::Large => Page::SIZE * 512,
+            PageSize::Huge => Page::SIZE * 512 * 512,
+        }
+    }
+
+    /// Returns whether the processor supports pages of this size. Huge pages are optional, and
+    /// an entry mapping one sets a reserved bit on processors without them.
+    pub fn is_supported(&self) -> bool {
+        match *self {
+            PageSize::Huge => {
+                // CPUID reports 1 GiB pages in bit 26 of EDX for the extended leaf 0x8000_0001.
+                let (_eax, _ebx, _ecx, edx): (u32, u32, u32, u32);
+                unsafe {
+                    asm!("cpuid"
+                         : "={eax}"(_eax), "={ebx}"(_ebx), "={ecx}"(_ecx), "={edx}"(edx)
+                         : "{eax}"(0x8000_0001u32), "{ecx}"(0u32)
+                         :
+                         : "volatile");
+                }
+                edx & (1 << 26) != 0
+            }
+            _ => true,
+        }
+    }
+}
+
 impl Add<usize> for Page {
     type Output = Page;
 
@@ -79,11 +146,20 @@ impl Iterator for PageIter {
     }
 }
 
-pub fn remap_kernel<A>(allocator: &mut A, info: &BootInformation) -> ActiveTable
+/// Creates a new table mapping the kernel sections with the right flags and switches to it. The
+/// frames from `reserved.0` on, `reserved.1` of them, hold the records of the allocator and are
+/// identity mapped as well. They have to be mapped before the switch, since the allocator may
+/// touch any of them when it hands out the frames for the new table.
+pub fn remap_kernel<A>(
+    allocator: &mut A,
+    info: &BootInformation,
+    reserved: (usize, usize),
+    temp: Page,
+) -> ActiveTable
 where
     A: frame::Allocator,
 {
-    let mut temp = TempPage::new(Page { id: 0xdeadaffe }, allocator);
+    let mut temp = TempPage::new(temp, allocator);
 
     let mut table = unsafe { ActiveTable::new() };
     let mut new = {
@@ -122,12 +198,65 @@ where
         for frame in Frame::range(mb_start, mb_end) {
             mapper.map_id(frame, PRESENT, allocator);
         }
+
+        // Identity map the records of the allocator.
+        let start = Frame::containing(reserved.0);
+        let end = Frame::containing(reserved.0 + (reserved.1 - 1) * Frame::SIZE);
+        for frame in Frame::range(start, end) {
+            mapper.map_id(frame, WRITABLE | NO_EXEC, allocator);
+        }
+
+        if cfg!(feature = "phys-offset") {
+            // Map all of physical memory with large pages.
+            let end = info.memory_map_tag()
+                .expect("Memory Map Tag required")
+                .memory_areas()
+                .map(|area| (area.base_addr + area.length) as usize)
+                .max()
+                .unwrap_or(0);
+            let size = PageSize::Large.bytes();
+            for addr in (0..(end + size - 1) / size).map(|i| i * size) {
+                mapper.map_huge(
+                    Page::containing(PHYS_OFFSET + addr),
+                    Frame::containing(addr),
+                    PageSize::Large,
+                    WRITABLE | NO_EXEC,
+                    allocator,
+                );
+            }
+        }
     });
 
+    // The new table marks data as not executable, which is only allowed with NXE enabled.
+    enable_nxe();
     let old = table.switch(new);
+    if cfg!(feature = "phys-offset") {
+        DIRECT.store(true, Ordering::Relaxed);
+    }
+    enable_write_protect();
 
+    // The old P4 lies in the kernel image, so it must not be freed.
     let old_p4 = Page::containing(old.frame.base());
     table.unmap(old_p4, allocator);
 
     table
 }
+
+/// Enables the `NO_EXEC` flag of page table entries. Without the NXE bit, the flag is a reserved
+/// bit and any access through an entry that has it set faults.
+fn enable_nxe() {
+    use x86_64::registers::msr::{rdmsr, wrmsr, IA32_EFER};
+
+    let nxe = 1 << 11;
+    unsafe {
+        let efer = rdmsr(IA32_EFER);
+        wrmsr(IA32_EFER, efer | nxe);
+    }
+}
+
+/// Makes pages without the `WRITABLE` flag read-only for the kernel as well.
+fn enable_write_protect() {
+    use x86_64::registers::control_regs::{cr0, cr0_write, Cr0};
+
+    unsafe { cr0_write(cr0() | Cr0::WRITE_PROTECT) };
+}
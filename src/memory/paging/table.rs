@@ -1,7 +1,7 @@
 use core::marker::PhantomData;
 use core::ops::{Deref, DerefMut, Index, IndexMut};
 use memory::frame::{self, Frame};
-use memory::paging::Page;
+use memory::paging::{self, MapError, Page, PageSize};
 use memory::paging::mapper::Mapper;
 use multiboot2::ElfSection;
 
@@ -35,6 +35,11 @@ where
             entry.free();
         }
     }
+
+    /// Returns whether every entry of the table is free.
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_free())
+    }
 }
 
 impl<L> Index<usize> for Table<L>
@@ -74,27 +79,50 @@ where
     fn next_addr(&self, index: usize) -> Option<usize> {
         let flags = self[index].flags();
         if flags.contains(PRESENT) && !(flags.contains(HUGE)) {
-            let addr = self as *const _ as usize;
-            Some((addr << 9) | (index << 12))
+            if paging::direct() {
+                Some(paging::phys_to_virt(self[index].frame().unwrap().base()))
+            } else {
+                let addr = self as *const _ as usize;
+                Some((addr << 9) | (index << 12))
+            }
         } else {
             None
         }
     }
 
     pub fn next_or_create<A>(&mut self, index: usize, allocator: &mut A) -> &mut Table<L::Next>
+    where
+        A: frame::Allocator,
+    {
+        self.try_next_or_create(index, allocator)
+            .expect("Could not create page table")
+    }
+
+    /// Returns the next table for the entry, and creates it if it does not exist yet.
+    ///
+    /// # Errors
+    /// Fails with `HugePageConflict` if the entry maps a huge page, and with
+    /// `FrameAllocationFailed` if no frame for the new table is available.
+    pub fn try_next_or_create<A>(
+        &mut self,
+        index: usize,
+        allocator: &mut A,
+    ) -> Result<&mut Table<L::Next>, MapError>
     where
         A: frame::Allocator,
     {
         if self.next(index).is_none() {
-            assert!(
-                !self.entries[index].flags().contains(HUGE),
-                "Mapping code does not support huge pages"
-            );
-            let frame = allocator.allocate().expect("No frames available");
+            if self.entries[index].flags().contains(HUGE) {
+                return Err(MapError::HugePageConflict);
+            }
+            let frame = match allocator.allocate() {
+                Some(frame) => frame,
+                None => return Err(MapError::FrameAllocationFailed),
+            };
             self.entries[index].set(frame, PRESENT | WRITABLE);
             self.next_mut(index).unwrap().reset();
         }
-        self.next_mut(index).unwrap()
+        Ok(self.next_mut(index).unwrap())
     }
 }
 
@@ -137,6 +165,9 @@ bitflags! {
         const DIRTY =           1 << 6,
         const HUGE =            1 << 7,
         const GLOBAL =          1 << 8,
+        /// Marks a read-only page whose frame is shared with another table and is copied on the
+        /// first write. The bit is one of those the processor leaves to the operating system.
+        const COPY_ON_WRITE =   1 << 9,
         const NO_EXEC =         1 << 63,
     }
 }
@@ -259,6 +290,12 @@ impl ActiveTable {
         use x86_64::instructions::tlb;
         use x86_64::registers::control_regs;
 
+        // With physical memory mapped, the inactive table can be edited directly.
+        if paging::direct() {
+            let mut mapper = unsafe { Mapper::at(&table.frame) };
+            return f(&mut mapper);
+        }
+
         {
             let backup = Frame::containing(control_regs::cr3().0 as usize);
 
@@ -307,6 +344,264 @@ impl InactiveTable {
 
         InactiveTable { frame: frame }
     }
+
+    /// Creates a copy of the active table for another address space. The P4 entries of the
+    /// private part from `start` to `end` get copies of their tables, while the pages mapped there
+    /// are shared: their frames get another reference, and writable pages become read-only and
+    /// [`COPY_ON_WRITE`](constant.COPY_ON_WRITE.html) in both tables until a write gives them a
+    /// copy of their frame.
+    ///
+    /// Every other P4 entry points to the same table as in the active table, so the kernel mapped
+    /// below them is the same for both tables, including the mappings it adds later on.
+    ///
+    /// # Panics
+    /// The function panics if the private part does not consist of whole P4 entries, or contains
+    /// the recursive one.
+    ///
+    /// # Errors
+    /// Fails with `FrameAllocationFailed` if there are not enough frames for the copies, and with
+    /// `HugePageConflict` if the private part contains a huge page, which cannot be copied on
+    /// write page by page. The partial copy is freed and the pages of the active table are
+    /// writable again in that case.
+    pub fn copy<A>(
+        table: &mut ActiveTable,
+        start: Page,
+        end: Page,
+        page: &mut TempPage,
+        allocator: &mut A,
+    ) -> Result<InactiveTable, MapError>
+    where
+        A: frame::Allocator,
+    {
+        use x86_64::instructions::tlb;
+
+        let (first, last) = private_entries(start, end);
+        let frame = match allocator.allocate() {
+            Some(frame) => frame,
+            None => return Err(MapError::FrameAllocationFailed),
+        };
+        let copy = InactiveTable::new(frame, table, page);
+
+        // The last entry is the recursive one, which the new table has set up already.
+        {
+            let p4 = page.map_table(copy.frame.clone(), table);
+            for index in (0..first).chain(last + 1..ENTRIES - 1) {
+                p4[index].0 = table.table()[index].0;
+            }
+        }
+        page.unmap(table);
+
+        for index in first..last + 1 {
+            if let Err(error) = copy_entry(table, page, allocator, &[index], &copy.frame) {
+                copy.free(start, end, table, page, allocator);
+                for index in first..last + 1 {
+                    unshare_entry(table, &[index], allocator);
+                }
+                tlb::flush_all();
+                return Err(error);
+            }
+        }
+        // Pages of the active table have become read-only.
+        tlb::flush_all();
+        Ok(copy)
+    }
+
+    /// Frees the table along with the tables of its private part from `start` to `end`, and drops
+    /// the references the pages mapped there hold to their frames. The tables of the other P4
+    /// entries are shared with the active table, see [`copy`](#method.copy), and are kept.
+    ///
+    /// # Panics
+    /// See [`copy`](#method.copy).
+    pub fn free<A>(
+        mut self,
+        start: Page,
+        end: Page,
+        table: &mut ActiveTable,
+        page: &mut TempPage,
+        allocator: &mut A,
+    ) where
+        A: frame::Allocator,
+    {
+        let (first, last) = private_entries(start, end);
+        table.with(&mut self, page, |mapper| for index in first..last + 1 {
+            free_entry(mapper, &[index], allocator);
+        });
+        allocator.deallocate(self.frame);
+    }
+}
+
+/// Returns the indices of the first and the last P4 entry of the private part of a table, which
+/// lies between the given pages.
+fn private_entries(start: Page, end: Page) -> (usize, usize) {
+    let size = PageSize::Huge.bytes() * ENTRIES;
+    assert!(
+        start <= end && start.base() % size == 0 && (end.base() + Page::SIZE) % size == 0 &&
+            end.p4_index() < 511,
+        "The private part must consist of whole P4 entries."
+    );
+    (start.p4_index(), end.p4_index())
+}
+
+/// Returns the entry of the table the mapper operates on that is reached with the given indices,
+/// starting with the index into the P4 table.
+fn entry_at<'a>(table: &'a Mapper, path: &[usize]) -> &'a Entry {
+    let p4 = table.table();
+    match path.len() {
+        1 => &p4[path[0]],
+        2 => &p4.next(path[0]).unwrap()[path[1]],
+        3 => &p4.next(path[0]).unwrap().next(path[1]).unwrap()[path[2]],
+        _ => {
+            &p4.next(path[0])
+                .unwrap()
+                .next(path[1])
+                .unwrap()
+                .next(path[2])
+                .unwrap()[path[3]]
+        }
+    }
+}
+
+/// Returns the entry reached with the given indices mutably, see [`entry_at`](fn.entry_at.html).
+fn entry_at_mut<'a>(table: &'a mut Mapper, path: &[usize]) -> &'a mut Entry {
+    let p4 = table.table_mut();
+    match path.len() {
+        1 => &mut p4[path[0]],
+        2 => &mut p4.next_mut(path[0]).unwrap()[path[1]],
+        3 => &mut p4.next_mut(path[0]).unwrap().next_mut(path[1]).unwrap()[path[2]],
+        _ => {
+            &mut p4.next_mut(path[0])
+                .unwrap()
+                .next_mut(path[1])
+                .unwrap()
+                .next_mut(path[2])
+                .unwrap()[path[3]]
+        }
+    }
+}
+
+/// Copies the entry of the active table that is reached with the given indices, see
+/// [`entry_at`](fn.entry_at.html), into the copy of its table in the given frame. The table the
+/// entry points to is copied along with the tables below it, while pages are shared as described
+/// for [`InactiveTable::copy`](struct.InactiveTable.html#method.copy).
+///
+/// A copied table is linked into its parent before it is filled, so a partial copy can be freed
+/// like a complete one.
+fn copy_entry<A>(
+    table: &mut ActiveTable,
+    page: &mut TempPage,
+    allocator: &mut A,
+    path: &[usize],
+    copy: &Frame,
+) -> Result<(), MapError>
+where
+    A: frame::Allocator,
+{
+    let index = path[path.len() - 1];
+    let (frame, mut flags) = {
+        let entry = entry_at(table, path);
+        match entry.frame() {
+            Some(frame) => (frame, entry.flags()),
+            None => return Ok(()),
+        }
+    };
+
+    // Entries of P1 tables map pages, huge ones cannot be copied on write.
+    if path.len() < 4 && flags.contains(HUGE) {
+        return Err(MapError::HugePageConflict);
+    }
+    if path.len() == 4 {
+        allocator.retain(&frame);
+        if flags.contains(WRITABLE) {
+            flags.remove(WRITABLE);
+            flags.insert(COPY_ON_WRITE);
+            entry_at_mut(table, path).set(frame.clone(), flags);
+        }
+        page.map_table(copy.clone(), table)[index].set(frame, flags);
+        page.unmap(table);
+        return Ok(());
+    }
+
+    let next = match allocator.allocate() {
+        Some(frame) => frame,
+        None => return Err(MapError::FrameAllocationFailed),
+    };
+    page.map_table(next.clone(), table).reset();
+    page.unmap(table);
+    page.map_table(copy.clone(), table)[index].set(next.clone(), flags);
+    page.unmap(table);
+
+    let mut indices = [0; 4];
+    indices[..path.len()].copy_from_slice(path);
+    for index in 0..ENTRIES {
+        indices[path.len()] = index;
+        copy_entry(table, page, allocator, &indices[..path.len() + 1], &next)?;
+    }
+    Ok(())
+}
+
+/// Frees the table the entry reached with the given indices points to, along with the tables
+/// below it, and drops the references of the pages mapped there. If the entry maps a page, only
+/// its reference is dropped. Copies made by [`copy_entry`](fn.copy_entry.html) hold no huge
+/// pages, so every entry above a P1 table points to a table.
+fn free_entry<A>(table: &Mapper, path: &[usize], allocator: &mut A)
+where
+    A: frame::Allocator,
+{
+    let (frame, flags) = {
+        let entry = entry_at(table, path);
+        match entry.frame() {
+            Some(frame) => (frame, entry.flags()),
+            None => return,
+        }
+    };
+
+    if path.len() == 4 {
+        allocator.deallocate(frame);
+        return;
+    }
+
+    let mut indices = [0; 4];
+    indices[..path.len()].copy_from_slice(path);
+    for index in 0..ENTRIES {
+        indices[path.len()] = index;
+        free_entry(table, &indices[..path.len() + 1], allocator);
+    }
+    allocator.deallocate(frame);
+}
+
+/// Makes the copy-on-write pages below the entry of the active table reached with the given
+/// indices writable again if no other table maps their frames anymore. This undoes the sharing
+/// of a failed copy once the copy has been freed.
+fn unshare_entry<A>(table: &mut ActiveTable, path: &[usize], allocator: &A)
+where
+    A: frame::Allocator,
+{
+    let (frame, mut flags) = {
+        let entry = entry_at(table, path);
+        match entry.frame() {
+            Some(frame) => (frame, entry.flags()),
+            None => return,
+        }
+    };
+
+    if path.len() == 4 {
+        if flags.contains(COPY_ON_WRITE) && allocator.references(&frame) == 1 {
+            flags.remove(COPY_ON_WRITE);
+            flags.insert(WRITABLE);
+            entry_at_mut(table, path).set(frame, flags);
+        }
+        return;
+    }
+    if flags.contains(HUGE) {
+        return;
+    }
+
+    let mut indices = [0; 4];
+    indices[..path.len()].copy_from_slice(path);
+    for index in 0..ENTRIES {
+        indices[path.len()] = index;
+        unshare_entry(table, &indices[..path.len() + 1], allocator);
+    }
 }
 
 pub struct TempPage {
@@ -326,6 +621,9 @@ impl TempPage {
     }
 
     pub fn map(&mut self, frame: Frame, table: &mut ActiveTable) -> usize {
+        if paging::direct() {
+            return paging::phys_to_virt(frame.base());
+        }
         table.map_to(self.page, frame, WRITABLE, &mut self.allocator);
         self.page.base()
     }
@@ -335,7 +633,9 @@ impl TempPage {
     }
 
     pub fn unmap(&mut self, table: &mut ActiveTable) {
-        table.unmap(self.page, &mut self.allocator);
+        if !paging::direct() {
+            table.unmap_temporary(self.page);
+        }
     }
 }
 
@@ -0,0 +1,183 @@
+use core::fmt;
+use super::{Mapper, PageSize};
+use super::table::{Entry, Flags, ACCESSED, DIRTY, HUGE, PRESENT};
+
+/// A contiguous run of present mappings with the same flags and page size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    /// The first virtual address of the run.
+    pub virt: usize,
+    /// The first physical address of the run.
+    pub phys: usize,
+    /// The size of the run in bytes.
+    pub size: usize,
+    /// The flags of the entries, without the accessed and dirty bits.
+    pub flags: Flags,
+    /// The size of the pages the run is mapped with.
+    pub page_size: PageSize,
+}
+
+impl Mapping {
+    /// Returns whether the mapping continues right where this one ends.
+    fn continues(&self, next: &Mapping) -> bool {
+        next.virt == self.virt + self.size && next.phys == self.phys + self.size &&
+            next.flags == self.flags && next.page_size == self.page_size
+    }
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#x}-{:#x} {:?} {:?}",
+            self.virt,
+            self.virt + self.size - 1,
+            self.phys,
+            self.phys + self.size - 1,
+            self.page_size,
+            self.flags
+        )
+    }
+}
+
+/// Iterates over the present mappings of the table a [`Mapper`](../mapper/struct.Mapper.html)
+/// operates on, coalescing contiguous runs. The recursive P4 entry is skipped.
+///
+/// The walk goes through the recursive mapping, so it works for an inactive table inside
+/// [`ActiveTable::with`](../table/struct.ActiveTable.html#method.with) as well.
+pub struct MappingIter<'a> {
+    mapper: &'a Mapper,
+    /// The indices into the P1, P2, P3 and P4 tables of the next entry to look at.
+    indices: [usize; 4],
+    done: bool,
+    pending: Option<Mapping>,
+}
+
+impl<'a> MappingIter<'a> {
+    pub fn new(mapper: &'a Mapper) -> MappingIter<'a> {
+        MappingIter {
+            mapper: mapper,
+            indices: [0; 4],
+            done: false,
+            pending: None,
+        }
+    }
+
+    /// Moves to the next entry of the table at the given level, with 1 being the P1 table.
+    fn advance(&mut self, level: usize) {
+        for index in self.indices[..level - 1].iter_mut() {
+            *index = 0;
+        }
+        self.indices[level - 1] += 1;
+        for i in level - 1..3 {
+            if self.indices[i] == 512 {
+                self.indices[i] = 0;
+                self.indices[i + 1] += 1;
+            }
+        }
+        // The last P4 entry maps the tables themselves.
+        if self.indices[3] >= 511 {
+            self.done = true;
+        }
+    }
+
+    /// Returns the virtual address of the current entry.
+    fn address(&self) -> usize {
+        let address = self.indices[3] << 39 | self.indices[2] << 30 | self.indices[1] << 21 |
+            self.indices[0] << 12;
+        // Sign extend the address to make it canonical.
+        if self.indices[3] >= 256 {
+            address | 0xffff_0000_0000_0000
+        } else {
+            address
+        }
+    }
+
+    /// Returns the mapping of a single entry.
+    fn mapping(&self, entry: &Entry, page_size: PageSize) -> Mapping {
+        let mut flags = entry.flags();
+        flags.remove(ACCESSED | DIRTY);
+        Mapping {
+            virt: self.address(),
+            phys: entry.frame().unwrap().base(),
+            size: page_size.bytes(),
+            flags: flags,
+            page_size: page_size,
+        }
+    }
+
+    /// Returns the next present entry that maps a page.
+    fn next_entry(&mut self) -> Option<Mapping> {
+        while !self.done {
+            let (i1, i2, i3, i4) = (
+                self.indices[0],
+                self.indices[1],
+                self.indices[2],
+                self.indices[3],
+            );
+            let mapper = self.mapper;
+            let p3 = match mapper.table().next(i4) {
+                Some(p3) => p3,
+                None => {
+                    self.advance(4);
+                    continue;
+                }
+            };
+
+            if !p3[i3].flags().contains(PRESENT) {
+                self.advance(3);
+                continue;
+            }
+            if p3[i3].flags().contains(HUGE) {
+                let mapping = self.mapping(&p3[i3], PageSize::Huge);
+                self.advance(3);
+                return Some(mapping);
+            }
+
+            let p2 = p3.next(i3).unwrap();
+            if !p2[i2].flags().contains(PRESENT) {
+                self.advance(2);
+                continue;
+            }
+            if p2[i2].flags().contains(HUGE) {
+                let mapping = self.mapping(&p2[i2], PageSize::Large);
+                self.advance(2);
+                return Some(mapping);
+            }
+
+            let p1 = p2.next(i2).unwrap();
+            let entry = &p1[i1];
+            let mapping = if entry.flags().contains(PRESENT) {
+                Some(self.mapping(entry, PageSize::Small))
+            } else {
+                None
+            };
+            self.advance(1);
+            if mapping.is_some() {
+                return mapping;
+            }
+        }
+        None
+    }
+}
+
+impl<'a> Iterator for MappingIter<'a> {
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping> {
+        let mut run = match self.pending.take().or_else(|| self.next_entry()) {
+            Some(mapping) => mapping,
+            None => return None,
+        };
+
+        while let Some(mapping) = self.next_entry() {
+            if run.continues(&mapping) {
+                run.size += mapping.size;
+            } else {
+                self.pending = Some(mapping);
+                break;
+            }
+        }
+        Some(run)
+    }
+}
@@ -1,6 +1,3 @@
-use memory::frame;
-use memory::paging::{ActiveTable, Page, PageIter, PRESENT, WRITABLE};
-
 pub struct Stack {
     top: usize,
     bottom: usize,
@@ -26,52 +23,3 @@ impl Stack {
         self.bottom
     }
 }
-
-pub struct StackAllocator {
-    range: PageIter,
-}
-
-impl StackAllocator {
-    pub fn new(range: PageIter) -> StackAllocator {
-        StackAllocator { range: range }
-    }
-
-    pub fn allocate<A>(
-        &mut self,
-        active_table: &mut ActiveTable,
-        allocator: &mut A,
-        pages: usize,
-    ) -> Option<Stack>
-    where
-        A: frame::Allocator,
-    {
-        if pages == 0 {
-            return None;
-        }
-
-        let mut range = self.range.clone();
-
-        let guard = range.next();
-        let start = range.next();
-        let end = if pages == 1 {
-            start
-        } else {
-            range.nth(pages - 2)
-        };
-
-
-        match (guard, start, end) {
-            (Some(_), Some(start), Some(end)) => {
-                self.range = range;
-
-                for page in Page::range(start, end) {
-                    active_table.map(page, PRESENT | WRITABLE, allocator);
-                }
-
-                let top = end.base() + Page::SIZE;
-                Some(Stack::new(top, start.base()))
-            }
-            _ => None,
-        }
-    }
-}
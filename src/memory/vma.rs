@@ -0,0 +1,482 @@
+//! Virtual memory areas: named, non-overlapping ranges of an address space.
+
+use core::fmt;
+use core::ops::DerefMut;
+use core::ptr;
+use error::Error;
+use memory::frame::{self, Frame};
+use memory::paging::{ActiveTable, Flags, InactiveTable, MapError, Mapper, Page, PageIter, TempPage,
+                     COPY_ON_WRITE, WRITABLE};
+
+/// The amount of areas an address space can hold.
+const AREAS: usize = 32;
+
+/// How the pages of an area are backed by frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    /// The pages are mapped when the area is inserted.
+    Mapped,
+    /// The pages are mapped on their first access.
+    Lazy,
+    /// The pages are never mapped, an access is a bug.
+    Guard,
+    /// The owner of the area maps the pages itself, like the heap growing into its region.
+    Reserved,
+}
+
+/// A named range of virtual memory.
+#[derive(Debug, Clone, Copy)]
+pub struct Area {
+    pub name: &'static str,
+    pub start: usize,
+    /// The address after the last byte of the area.
+    pub end: usize,
+    pub flags: Flags,
+    pub kind: Kind,
+}
+
+impl Area {
+    pub fn new(name: &'static str, start: usize, size: usize, flags: Flags, kind: Kind) -> Area {
+        assert!(
+            start % Page::SIZE == 0 && size % Page::SIZE == 0 && size > 0,
+            "Areas must consist of whole pages."
+        );
+        Area {
+            name: name,
+            start: start,
+            end: start + size,
+            flags: flags,
+            kind: kind,
+        }
+    }
+
+    pub fn contains(&self, addr: usize) -> bool {
+        addr >= self.start && addr < self.end
+    }
+
+    fn overlaps(&self, other: &Area) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+
+    fn pages(&self) -> PageIter {
+        Page::range(Page::containing(self.start), Page::containing(self.end - 1))
+    }
+}
+
+impl fmt::Display for Area {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} {} {:?} {:?}",
+            self.start,
+            self.end - 1,
+            self.name,
+            self.kind,
+            self.flags
+        )
+    }
+}
+
+/// The errors that can occur when managing the areas of an address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaError {
+    /// The area overlaps another one.
+    Overlap,
+    /// The area does not fit into the address space, or there is no free range large enough.
+    OutOfSpace,
+    /// The address space cannot hold more areas.
+    TooManyAreas,
+    /// The pages of the area could not be mapped.
+    Map(MapError),
+}
+
+impl fmt::Display for AreaError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AreaError::Map(error) => write!(f, "{}: {}", self.description(), error),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for AreaError {
+    fn description(&self) -> &str {
+        match *self {
+            AreaError::Overlap => "Area overlaps another area",
+            AreaError::OutOfSpace => "Not enough free virtual memory",
+            AreaError::TooManyAreas => "Too many areas",
+            AreaError::Map(_) => "Could not map area",
+        }
+    }
+
+    fn cause(&self) -> Option<&Error> {
+        match *self {
+            AreaError::Map(ref error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// The areas of an address space, which lie between a start and an end address.
+#[derive(Clone)]
+pub struct Areas {
+    start: usize,
+    end: usize,
+    areas: [Option<Area>; AREAS],
+}
+
+impl Areas {
+    /// Creates an empty set of areas managing the virtual memory from `start` up to the exclusive
+    /// `end` address.
+    pub fn new(start: usize, end: usize) -> Areas {
+        Areas {
+            start: start,
+            end: end,
+            areas: [None; AREAS],
+        }
+    }
+
+    /// Adds an area.
+    ///
+    /// # Errors
+    /// Fails with `OutOfSpace` if the area lies outside of the address space, with `Overlap` if
+    /// it overlaps another area and with `TooManyAreas` if there is no room for it.
+    pub fn insert(&mut self, area: Area) -> Result<(), AreaError> {
+        if area.start < self.start || area.end > self.end {
+            return Err(AreaError::OutOfSpace);
+        }
+        if self.iter().any(|other| other.overlaps(&area)) {
+            return Err(AreaError::Overlap);
+        }
+
+        match self.areas.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(area);
+                Ok(())
+            }
+            None => Err(AreaError::TooManyAreas),
+        }
+    }
+
+    /// Returns the lowest free range of the given size.
+    pub fn find_free(&self, size: usize) -> Option<usize> {
+        // A free range starts either at the start of the space or at the end of an area.
+        let candidates = Some(self.start)
+            .into_iter()
+            .chain(self.iter().map(|area| area.end));
+        candidates
+            .filter(|&start| {
+                let area = Area {
+                    name: "",
+                    start: start,
+                    end: start + size,
+                    flags: Flags::empty(),
+                    kind: Kind::Guard,
+                };
+                area.end <= self.end && !self.iter().any(|other| other.overlaps(&area))
+            })
+            .min()
+    }
+
+    /// Adds an area of the given size at the lowest free range and returns its start address.
+    ///
+    /// # Errors
+    /// See [`insert`](#method.insert).
+    pub fn allocate(
+        &mut self,
+        name: &'static str,
+        size: usize,
+        flags: Flags,
+        kind: Kind,
+    ) -> Result<usize, AreaError> {
+        let start = match self.find_free(size) {
+            Some(start) => start,
+            None => return Err(AreaError::OutOfSpace),
+        };
+        self.insert(Area::new(name, start, size, flags, kind))
+            .map(|_| start)
+    }
+
+    /// Removes the area starting at the given address and returns it.
+    pub fn remove(&mut self, start: usize) -> Option<Area> {
+        self.areas
+            .iter_mut()
+            .find(|slot| slot.map_or(false, |area| area.start == start))
+            .and_then(|slot| slot.take())
+    }
+
+    /// Returns the area the address lies in.
+    pub fn find(&self, addr: usize) -> Option<&Area> {
+        self.iter().find(|area| area.contains(addr))
+    }
+
+    /// Returns an iterator over the areas, in no particular order.
+    pub fn iter(&self) -> AreaIter {
+        AreaIter {
+            areas: &self.areas,
+            index: 0,
+        }
+    }
+
+    /// Returns the areas that lie between `start` and the exclusive `end` address, as a set of
+    /// areas managing that range.
+    fn within(&self, start: usize, end: usize) -> Areas {
+        let mut areas = Areas::new(start, end);
+        let inside = self.iter().filter(|area| area.start >= start && area.end <= end);
+        for (slot, area) in areas.areas.iter_mut().zip(inside) {
+            *slot = Some(*area);
+        }
+        areas
+    }
+}
+
+/// Iterates over the [`Areas`](struct.Areas.html) of an address space.
+pub struct AreaIter<'a> {
+    areas: &'a [Option<Area>],
+    index: usize,
+}
+
+impl<'a> Iterator for AreaIter<'a> {
+    type Item = &'a Area;
+
+    fn next(&mut self) -> Option<&'a Area> {
+        while self.index < self.areas.len() {
+            self.index += 1;
+            if let Some(ref area) = self.areas[self.index - 1] {
+                return Some(area);
+            }
+        }
+        None
+    }
+}
+
+/// A page table together with the areas of virtual memory it maps.
+pub struct AddressSpace<T> {
+    table: T,
+    areas: Areas,
+}
+
+impl<T> AddressSpace<T> {
+    pub fn new(table: T, areas: Areas) -> AddressSpace<T> {
+        AddressSpace {
+            table: table,
+            areas: areas,
+        }
+    }
+
+    pub fn table(&self) -> &T {
+        &self.table
+    }
+
+    pub fn table_mut(&mut self) -> &mut T {
+        &mut self.table
+    }
+
+    pub fn areas(&self) -> &Areas {
+        &self.areas
+    }
+}
+
+impl<T> AddressSpace<T>
+where
+    T: DerefMut<Target = Mapper>,
+{
+    /// Adds an area and maps its pages if it is of the `Mapped` kind.
+    ///
+    /// # Errors
+    /// See [`Areas::insert`](struct.Areas.html#method.insert). Fails with `Map` if the pages
+    /// cannot be mapped, in which case the area is not added.
+    pub fn insert<A>(&mut self, area: Area, allocator: &mut A) -> Result<(), AreaError>
+    where
+        A: frame::Allocator,
+    {
+        self.areas.insert(area)?;
+        if area.kind != Kind::Mapped {
+            return Ok(());
+        }
+
+        for page in area.pages() {
+            if let Err(error) = self.table.try_map(page, area.flags, allocator) {
+                let first = Page::containing(area.start);
+                let mapped = Page::range(first, Page::containing(page.base() - 1));
+                self.table
+                    .unmap_range(mapped, allocator)
+                    .expect("Pages mapped for the area could not be unmapped");
+                self.areas.remove(area.start);
+                return Err(AreaError::Map(error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds an area of the given size at the lowest free range, maps it according to its kind and
+    /// returns its start address.
+    ///
+    /// # Errors
+    /// See [`insert`](#method.insert).
+    pub fn allocate<A>(
+        &mut self,
+        name: &'static str,
+        size: usize,
+        flags: Flags,
+        kind: Kind,
+        allocator: &mut A,
+    ) -> Result<usize, AreaError>
+    where
+        A: frame::Allocator,
+    {
+        let start = match self.areas.find_free(size) {
+            Some(start) => start,
+            None => return Err(AreaError::OutOfSpace),
+        };
+        self.insert(Area::new(name, start, size, flags, kind), allocator)
+            .map(|_| start)
+    }
+
+    /// Removes the area starting at the given address, unmaps its pages and frees their frames.
+    /// Pages of `Reserved` areas are left to their owner.
+    pub fn remove<A>(&mut self, start: usize, allocator: &mut A) -> Option<Area>
+    where
+        A: frame::Allocator,
+    {
+        let area = self.areas.remove(start);
+        if let Some(area) = area {
+            if area.kind == Kind::Mapped || area.kind == Kind::Lazy {
+                self.table
+                    .unmap_present(area.pages(), allocator)
+                    .expect("Area contains a huge page");
+            }
+        }
+        area
+    }
+}
+
+impl AddressSpace<ActiveTable> {
+    /// Maps the page containing the address if it belongs to a `Lazy` area. The page is filled
+    /// with zeros, so it does not show what its frame held before. Returns whether the page has
+    /// been mapped.
+    pub fn fault<A>(&mut self, addr: usize, allocator: &mut A) -> bool
+    where
+        A: frame::Allocator,
+    {
+        let flags = match self.areas.find(addr) {
+            Some(area) if area.kind == Kind::Lazy => area.flags,
+            _ => return false,
+        };
+
+        // The page is cleared through its own mapping before it gets the flags of its area.
+        let page = Page::containing(addr);
+        if self.table.try_map(page, flags | WRITABLE, allocator).is_err() {
+            return false;
+        }
+        unsafe { ptr::write_bytes(page.base() as *mut u8, 0, Page::SIZE) };
+        flags.contains(WRITABLE) || self.table.protect(Page::range(page, page), flags).is_ok()
+    }
+
+    /// Creates an address space that shares the kernel part of this one and gets a copy of the
+    /// private part between the given addresses, along with the areas in there. The pages of the
+    /// private part are shared copy-on-write, see
+    /// [`InactiveTable::copy`](../paging/struct.InactiveTable.html#method.copy) and
+    /// [`copy_on_write`](#method.copy_on_write).
+    ///
+    /// # Errors
+    /// See [`InactiveTable::copy`](../paging/struct.InactiveTable.html#method.copy).
+    pub fn fork<A>(
+        &mut self,
+        private: (usize, usize),
+        page: &mut TempPage,
+        allocator: &mut A,
+    ) -> Result<AddressSpace<InactiveTable>, MapError>
+    where
+        A: frame::Allocator,
+    {
+        let start = Page::containing(private.0);
+        let end = Page::containing(private.1 - 1);
+        let table = InactiveTable::copy(&mut self.table, start, end, page, allocator)?;
+        Ok(AddressSpace::new(table, self.areas.within(private.0, private.1)))
+    }
+
+    /// Activates the table of a space created by [`fork`](#method.fork) and takes over its
+    /// areas. The given space is left with the table and the private areas that were active
+    /// before, so switching again returns to them. The kernel areas stay, as the kernel part of
+    /// the tables is shared.
+    ///
+    /// # Errors
+    /// Fails with `TooManyAreas` if the kernel areas and the areas of the given space do not fit
+    /// into one space. Nothing is switched in that case.
+    pub fn switch(&mut self, space: &mut AddressSpace<InactiveTable>) -> Result<(), AreaError> {
+        let private = self.areas.within(space.areas.start, space.areas.end);
+        let mut areas = self.areas.clone();
+        for area in private.iter() {
+            areas.remove(area.start);
+        }
+        for area in space.areas.iter() {
+            areas.insert(*area)?;
+        }
+
+        let frame = space.table.frame.clone();
+        space.table = self.table.switch(InactiveTable { frame: frame });
+        self.areas = areas;
+        space.areas = private;
+        Ok(())
+    }
+
+    /// Makes the copy-on-write page containing the address writable again, after a write to it
+    /// faulted. The page gets a copy of its frame, unless no other table maps the frame anymore.
+    /// Returns whether the page was copy-on-write and is now writable.
+    ///
+    /// The kernel calls this from its page fault handler only when the memory controller is not
+    /// held, so a write to a copy-on-write page from inside `memory::with` is not handled.
+    pub fn copy_on_write<A>(&mut self, addr: usize, page: &mut TempPage, allocator: &mut A) -> bool
+    where
+        A: frame::Allocator,
+    {
+        let target = Page::containing(addr);
+        let mut flags = match self.table.flags(target) {
+            Some(flags) if flags.contains(COPY_ON_WRITE) => flags,
+            _ => return false,
+        };
+        flags.remove(COPY_ON_WRITE);
+        flags.insert(WRITABLE);
+
+        let frame = Frame::containing(self.table.translate(target.base()).unwrap());
+        if allocator.references(&frame) == 1 {
+            return self.table.protect(Page::range(target, target), flags).is_ok();
+        }
+
+        let copy = match allocator.allocate() {
+            Some(copy) => copy,
+            None => return false,
+        };
+        {
+            let destination = page.map(copy.clone(), &mut self.table);
+            unsafe {
+                ptr::copy_nonoverlapping(
+                    target.base() as *const u8,
+                    destination as *mut u8,
+                    Page::SIZE,
+                );
+            }
+        }
+        page.unmap(&mut self.table);
+
+        // Give up the reference the page held to the shared frame.
+        let shared = self.table.replace(target, copy, flags);
+        allocator.deallocate(shared);
+        true
+    }
+}
+
+impl AddressSpace<InactiveTable> {
+    /// Frees the table of a space created by [`fork`](struct.AddressSpace.html#method.fork)
+    /// along with its private part. The frames of its pages are freed unless another table still
+    /// maps them.
+    pub fn free<A>(self, table: &mut ActiveTable, page: &mut TempPage, allocator: &mut A)
+    where
+        A: frame::Allocator,
+    {
+        let start = Page::containing(self.areas.start);
+        let end = Page::containing(self.areas.end - 1);
+        self.table.free(start, end, table, page, allocator);
+    }
+}
@@ -35,6 +35,18 @@ impl<T: ?Sized> Mutex<T> {
             data: unsafe { &mut *self.data.get() },
         }
     }
+
+    /// Acquires the lock if it is free, without spinning.
+    pub fn try_lock(&self) -> Option<MutexGuard<T>> {
+        if self.lock.compare_and_swap(false, true, Ordering::Acquire) {
+            None
+        } else {
+            Some(MutexGuard {
+                lock: &self.lock,
+                data: unsafe { &mut *self.data.get() },
+            })
+        }
+    }
 }
 
 pub struct MutexGuard<'a, T: ?Sized + 'a> {
@@ -50,7 +50,7 @@ impl Logger for PrintLogger {
     }
 }
 
-#[derive(PartialOrd, Ord, PartialEq, Eq)]
+#[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
 pub enum Level {
     Info,
     Warn,